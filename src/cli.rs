@@ -35,6 +35,31 @@ pub fn app<'a, 'b>() -> App<'a, 'b> {
         .help("Either the name of the section as shown in the html output (e.g. 2.1., note the trailing '.') \
             or a glob pattern matching the chapter name, e.g. 'Intro*'. \
             If the pattern is invalid, it will be ignored silently, and the program will fail if no pattern matches.");
+    let keep_temp = Arg::with_name("keep-temp")
+        .long("keep-temp")
+        .required(false)
+        .help(
+            "If set, temporary directories that code blocks were executed in are kept \
+             for inspection instead of being removed, and their paths are printed.",
+        );
+    let no_cache = Arg::with_name("no-cache")
+        .long("no-cache")
+        .required(false)
+        .help(
+            "If set, all code blocks are executed even if their output is cached, \
+             and the cache is left as it is.",
+        );
+    let jobs = Arg::with_name("jobs")
+        .long("jobs")
+        .short("j")
+        .required(false)
+        .takes_value(true)
+        .value_name("N")
+        .default_value("1")
+        .help(
+            "The amount of chapters to execute code blocks of at the same time, or 0 for one per CPU. \
+             Chapters using the same session are executed one after another.",
+        );
     let build = App::new("build")
         .about(
             "Build the `mdbook` compatible book in the current working directory \
@@ -52,6 +77,18 @@ pub fn app<'a, 'b>() -> App<'a, 'b> {
                      It's useful to review the preprocessor result.",
                 ),
         )
+        .arg(
+            Arg::with_name("update-snapshots")
+                .long("update-snapshots")
+                .required(false)
+                .help(
+                    "If set, snapshot files are written with the output of their code block \
+                     instead of failing the build if they don't match it.",
+                ),
+        )
+        .arg(keep_temp.clone())
+        .arg(no_cache.clone())
+        .arg(jobs.clone())
         .arg(book_path.clone())
         .arg(selector.clone());
 
@@ -64,14 +101,25 @@ pub fn app<'a, 'b>() -> App<'a, 'b> {
                 .default_value("50")
                 .help("The amount of characters printed per second."),
         )
-        .arg(book_path)
+        .arg(keep_temp)
+        .arg(no_cache)
+        .arg(jobs)
+        .arg(book_path.clone())
         .arg(selector);
 
+    let clean = App::new("clean")
+        .about(
+            "Remove the cached output of code blocks of the book in the current working directory \
+             or in the given location.",
+        )
+        .arg(book_path);
+
     app.name("termbook")
         .after_help(
             "Also have a look at the online documentation at https://byron.github.io/termbook",
         )
         .subcommand(build)
         .subcommand(playback)
+        .subcommand(clean)
         .subcommand(completions)
 }
@@ -8,7 +8,7 @@ mod parse;
 mod types;
 
 use std::process;
-use termbook::mdbook::errors::Error;
+use termbook::{mdbook::errors::Error, Diagnostics};
 
 use clap::ArgMatches;
 use std::io::{stderr, Write};
@@ -19,6 +19,23 @@ where
     W: Write,
 {
     let e = e.into();
+    match Diagnostics::find(&e) {
+        Some(diagnostics) => {
+            writeln!(w, "{}", e).ok();
+            for diagnostic in diagnostics.iter() {
+                write!(w, "{}: ", diagnostic).ok();
+                print_chain(&diagnostic.cause, &mut w);
+                writeln!(w, "{}", diagnostic.excerpt).ok();
+            }
+        }
+        None => print_chain(&e, &mut w),
+    }
+}
+
+fn print_chain<W>(e: &Error, w: &mut W)
+where
+    W: Write,
+{
     let causes = e.iter().collect::<Vec<_>>();
     let num_causes = causes.len();
     for (index, cause) in causes.iter().enumerate() {
@@ -65,18 +82,33 @@ fn main() {
         }
         ("play", Some(args)) => {
             let ctx = ok_or_exit(parse::playback_context_from(args));
-            let mut book = ok_or_exit(termbook::load(&ctx.path, ctx.globs.clone()));
+            let preprocessor = termbook::RunCodeBlocks::new(ctx.globs.clone())
+                .keep_temp_dirs(ctx.keep_temp)
+                .cache(!ctx.no_cache)
+                .jobs(ctx.jobs);
+            let mut book = ok_or_exit(termbook::load_with_preprocessor(&ctx.path, preprocessor));
             book.with_renderer(termbook::Playback::new(ctx.chars_per_second, ctx.globs));
             ok_or_exit(book.build());
         }
         ("build", Some(args)) => {
             let ctx = ok_or_exit(parse::build_context_from(args));
-            let mut book = ok_or_exit(termbook::load(&ctx.path, ctx.globs));
+            let preprocessor = termbook::RunCodeBlocks::new(ctx.globs)
+                .keep_temp_dirs(ctx.keep_temp)
+                .update_snapshots(ctx.update_snapshots)
+                .cache(!ctx.no_cache)
+                .jobs(ctx.jobs);
+            let mut book = ok_or_exit(termbook::load_with_preprocessor(&ctx.path, preprocessor));
             if ctx.rewrite {
-                book.with_renderer(termbook::Rewrite::default());
+                book.with_renderer(termbook::Rewrite);
             }
             ok_or_exit(book.build());
         }
+        ("clean", Some(args)) => {
+            let ctx = parse::clean_context_from(args);
+            if let Some(dir) = ok_or_exit(termbook::clean(&ctx.path)) {
+                eprintln!("Removed cached output at '{}'.", dir.display());
+            }
+        }
         _ => usage_and_exit(&matches),
     };
 }
@@ -5,7 +5,7 @@ use std::str::FromStr;
 use std::env::current_dir;
 use termbook::mdbook::errors::Error;
 
-use crate::types::{BuildContext, PlaybackContext};
+use crate::types::{BuildContext, CleanContext, PlaybackContext};
 
 pub fn generate_completions(mut app: App, args: &ArgMatches) -> Result<(), Error> {
     let shell = args.value_of("shell")
@@ -40,6 +40,12 @@ pub fn playback_context_from(args: &ArgMatches) -> Result<PlaybackContext, Error
             .map(Path::new)
             .map(Into::into)
             .unwrap_or_else(|| current_dir().expect("current dir available")),
+        keep_temp: args.is_present("keep-temp"),
+        no_cache: args.is_present("no-cache"),
+        jobs: args.value_of("jobs")
+            .expect("at least default")
+            .parse()
+            .map_err(|e| Error::from(format!("Invalid amount of jobs: {}", e)))?,
     })
 }
 pub fn build_context_from(args: &ArgMatches) -> Result<BuildContext, Error> {
@@ -52,5 +58,21 @@ pub fn build_context_from(args: &ArgMatches) -> Result<BuildContext, Error> {
             .map(Into::into)
             .unwrap_or_else(|| current_dir().expect("current dir available")),
         rewrite: args.is_present("rewrite"),
+        keep_temp: args.is_present("keep-temp"),
+        no_cache: args.is_present("no-cache"),
+        jobs: args.value_of("jobs")
+            .expect("at least default")
+            .parse()
+            .map_err(|e| Error::from(format!("Invalid amount of jobs: {}", e)))?,
+        update_snapshots: args.is_present("update-snapshots"),
     })
 }
+
+pub fn clean_context_from(args: &ArgMatches) -> CleanContext {
+    CleanContext {
+        path: args.value_of_os("path")
+            .map(Path::new)
+            .map(Into::into)
+            .unwrap_or_else(|| current_dir().expect("current dir available")),
+    }
+}
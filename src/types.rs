@@ -4,10 +4,21 @@ pub struct PlaybackContext {
     pub globs: Vec<String>,
     pub chars_per_second: usize,
     pub path: PathBuf,
+    pub keep_temp: bool,
+    pub no_cache: bool,
+    pub jobs: usize,
 }
 
 pub struct BuildContext {
     pub globs: Vec<String>,
     pub path: PathBuf,
     pub rewrite: bool,
+    pub keep_temp: bool,
+    pub no_cache: bool,
+    pub jobs: usize,
+    pub update_snapshots: bool,
+}
+
+pub struct CleanContext {
+    pub path: PathBuf,
 }
@@ -0,0 +1,251 @@
+use std::fmt::Write;
+
+/// An escape sequence, or a run of text without any.
+enum Token<'a> {
+    Text(&'a str),
+    /// The parameters of a 'Select Graphic Rendition' sequence, like `1;31` in `\x1b[1;31m`.
+    Sgr(&'a str),
+    /// Any other escape sequence.
+    Other,
+}
+
+/// Split `input` into text and escape sequences.
+fn tokenize(input: &str) -> Vec<Token<'_>> {
+    let bytes = input.as_bytes();
+    let mut tokens = Vec::new();
+    let mut text_start = 0;
+    let mut pos = 0;
+    while pos < bytes.len() {
+        if bytes[pos] != 0x1b {
+            pos += 1;
+            continue;
+        }
+        if text_start < pos {
+            tokens.push(Token::Text(&input[text_start..pos]));
+        }
+        let start = pos;
+        pos += 1;
+        match bytes.get(pos) {
+            // Control Sequence Introducer, terminated by a byte in the range '@' to '~'.
+            Some(b'[') => {
+                pos += 1;
+                while pos < bytes.len() && !(0x40..=0x7e).contains(&bytes[pos]) {
+                    pos += 1;
+                }
+                let params = &input[start + 2..pos.min(bytes.len())];
+                pos += 1;
+                tokens.push(if bytes.get(pos - 1) == Some(&b'm') {
+                    Token::Sgr(params)
+                } else {
+                    Token::Other
+                });
+            }
+            // Operating System Command, terminated by BEL or ESC \.
+            Some(b']') => {
+                pos += 1;
+                while pos < bytes.len() {
+                    if bytes[pos] == 0x07 {
+                        pos += 1;
+                        break;
+                    }
+                    if bytes[pos] == 0x1b && bytes.get(pos + 1) == Some(&b'\\') {
+                        pos += 2;
+                        break;
+                    }
+                    pos += 1;
+                }
+                tokens.push(Token::Other);
+            }
+            Some(b) if b.is_ascii() => {
+                pos += 1;
+                tokens.push(Token::Other);
+            }
+            _ => tokens.push(Token::Other),
+        }
+        pos = pos.min(bytes.len());
+        text_start = pos;
+    }
+    if text_start < bytes.len() {
+        tokens.push(Token::Text(&input[text_start..]));
+    }
+    tokens
+}
+
+/// Remove all escape sequences from `input`.
+pub(crate) fn strip(input: &str) -> String {
+    tokenize(input)
+        .into_iter()
+        .filter_map(|token| match token {
+            Token::Text(text) => Some(text),
+            _ => None,
+        })
+        .collect()
+}
+
+#[derive(Clone, Default, PartialEq)]
+struct Style {
+    bold: bool,
+    dim: bool,
+    italic: bool,
+    underline: bool,
+    foreground: Option<Color>,
+    background: Option<Color>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Color {
+    /// One of the 16 colors of the terminal's palette.
+    Named(u8),
+    Rgb(u8, u8, u8),
+}
+
+const COLOR_NAMES: [&str; 8] = [
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+];
+
+impl Color {
+    /// Parse an extended color following `38` or `48`, like `5;208` or `2;255;128;0`.
+    fn parse_extended<'a>(params: &mut impl Iterator<Item = &'a str>) -> Option<Color> {
+        let mut next = || params.next().and_then(|p| p.parse::<u8>().ok());
+        match next()? {
+            5 => {
+                let index = next()?;
+                Some(match index {
+                    0..=15 => Color::Named(index),
+                    16..=231 => {
+                        let level = |v: u8| if v == 0 { 0 } else { 55 + v * 40 };
+                        let index = index - 16;
+                        Color::Rgb(level(index / 36), level(index / 6 % 6), level(index % 6))
+                    }
+                    _ => {
+                        let gray = 8 + (index - 232) * 10;
+                        Color::Rgb(gray, gray, gray)
+                    }
+                })
+            }
+            2 => Some(Color::Rgb(next()?, next()?, next()?)),
+            _ => None,
+        }
+    }
+
+    fn class(self, prefix: &str) -> Option<String> {
+        match self {
+            Color::Named(index) => Some(format!(
+                "{}{}{}",
+                prefix,
+                if index >= 8 { "bright-" } else { "" },
+                COLOR_NAMES[usize::from(index % 8)]
+            )),
+            Color::Rgb(..) => None,
+        }
+    }
+
+    fn css(self) -> Option<String> {
+        match self {
+            Color::Rgb(r, g, b) => Some(format!("#{:02x}{:02x}{:02x}", r, g, b)),
+            Color::Named(_) => None,
+        }
+    }
+}
+
+impl Style {
+    fn apply(&mut self, params: &str) {
+        let mut params = params.split(';');
+        while let Some(param) = params.next() {
+            match param.parse::<u8>().unwrap_or(0) {
+                0 => *self = Style::default(),
+                1 => self.bold = true,
+                2 => self.dim = true,
+                3 => self.italic = true,
+                4 => self.underline = true,
+                22 => {
+                    self.bold = false;
+                    self.dim = false;
+                }
+                23 => self.italic = false,
+                24 => self.underline = false,
+                n @ 30..=37 => self.foreground = Some(Color::Named(n - 30)),
+                38 => self.foreground = Color::parse_extended(&mut params),
+                39 => self.foreground = None,
+                n @ 40..=47 => self.background = Some(Color::Named(n - 40)),
+                48 => self.background = Color::parse_extended(&mut params),
+                49 => self.background = None,
+                n @ 90..=97 => self.foreground = Some(Color::Named(n - 90 + 8)),
+                n @ 100..=107 => self.background = Some(Color::Named(n - 100 + 8)),
+                _ => {}
+            }
+        }
+    }
+
+    /// The attributes of a `<span>` element displaying text in this style.
+    fn attributes(&self) -> String {
+        let mut classes: Vec<String> = [
+            (self.bold, "ansi-bold"),
+            (self.dim, "ansi-dim"),
+            (self.italic, "ansi-italic"),
+            (self.underline, "ansi-underline"),
+        ]
+        .iter()
+        .filter(|(enabled, _)| *enabled)
+        .map(|(_, class)| (*class).to_owned())
+        .collect();
+        classes.extend(self.foreground.and_then(|c| c.class("ansi-")));
+        classes.extend(self.background.and_then(|c| c.class("ansi-bg-")));
+
+        let mut styles = Vec::new();
+        styles.extend(self.foreground.and_then(Color::css).map(|c| format!("color:{}", c)));
+        styles.extend(
+            self.background
+                .and_then(Color::css)
+                .map(|c| format!("background-color:{}", c)),
+        );
+
+        let mut attributes = String::new();
+        if !classes.is_empty() {
+            write!(attributes, " class=\"{}\"", classes.join(" ")).ok();
+        }
+        if !styles.is_empty() {
+            write!(attributes, " style=\"{}\"", styles.join(";")).ok();
+        }
+        attributes
+    }
+}
+
+/// Convert `input` into escaped HTML, where styled text is placed into `<span>` elements
+/// with classes like `ansi-bold`, `ansi-red` or `ansi-bg-bright-blue`.
+///
+/// Colors beyond the 16 of the terminal's palette are set as inline style.
+/// All escape sequences other than those selecting the style are removed.
+pub(crate) fn to_html(input: &str) -> String {
+    let mut html = String::with_capacity(input.len());
+    let mut style = Style::default();
+    for token in tokenize(input) {
+        match token {
+            Token::Text(text) => {
+                let attributes = style.attributes();
+                if !attributes.is_empty() {
+                    write!(html, "<span{}>", attributes).ok();
+                }
+                escape_html(text, &mut html);
+                if !attributes.is_empty() {
+                    html.push_str("</span>");
+                }
+            }
+            Token::Sgr(params) => style.apply(params),
+            Token::Other => {}
+        }
+    }
+    html
+}
+
+pub(crate) fn escape_html(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use crate::exec::Completion;
+
+use mdbook::errors::{Error, Result};
+use sha1::{Digest, Sha1};
+
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// The directory holding the results of executed programs, relative to the book root.
+const CACHE_DIR: &str = ".termbook/cache";
+
+/// A directory of the results of executed programs, stored by the hash of everything
+/// they depend on.
+pub(crate) struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new(book_root: &Path) -> Cache {
+        Cache {
+            dir: book_root.join(CACHE_DIR),
+        }
+    }
+
+    /// The result stored for `key`, if there is one which can be read.
+    pub fn get(&self, key: &str) -> Option<Completion> {
+        let data = fs::read(self.dir.join(key)).ok()?;
+        let header_end = data.iter().position(|b| *b == b'\n')?;
+        let header = std::str::from_utf8(&data[..header_end]).ok()?;
+        let mut status_and_len = header.splitn(2, ' ');
+        let status = status_and_len.next()?.parse().ok()?;
+        let stdout_len: usize = status_and_len.next()?.parse().ok()?;
+        let output = &data[header_end + 1..];
+        if output.len() < stdout_len {
+            return None;
+        }
+        Some(Completion {
+            status: Some(status),
+            stdout: output[..stdout_len].to_owned(),
+            stderr: output[stdout_len..].to_owned(),
+        })
+    }
+
+    /// Store the result of a program which exited with a status for `key`.
+    ///
+    /// The file is written completely before it is visible, so concurrent builds never
+    /// read partial results.
+    pub fn put(&self, key: &str, completion: &Completion) -> io::Result<()> {
+        let status = completion
+            .status
+            .expect("only results of programs that exited to be stored");
+        fs::create_dir_all(&self.dir)?;
+        let mut file = tempfile::NamedTempFile::new_in(&self.dir)?;
+        writeln!(file, "{} {}", status, completion.stdout.len())?;
+        file.write_all(&completion.stdout)?;
+        file.write_all(&completion.stderr)?;
+        file.persist(self.dir.join(key)).map_err(|e| e.error)?;
+        Ok(())
+    }
+}
+
+/// Compute a cache key from all values an execution depends on.
+pub(crate) struct KeyBuilder(Sha1);
+
+impl KeyBuilder {
+    pub fn new() -> KeyBuilder {
+        let mut builder = KeyBuilder(Sha1::new());
+        builder.add("version", env!("CARGO_PKG_VERSION"));
+        builder
+    }
+
+    /// Add the `value` of the input called `name`.
+    pub fn add(&mut self, name: &str, value: impl AsRef<[u8]>) -> &mut KeyBuilder {
+        let value = value.as_ref();
+        // Lengths keep the boundaries between values, so they can't be confused with each other.
+        for part in &[name.as_bytes(), value] {
+            self.0.input((part.len() as u64).to_le_bytes());
+            self.0.input(part);
+        }
+        self
+    }
+
+    /// Add the identity of the file at `path`, which changes whenever it is modified or replaced.
+    pub fn add_file(&mut self, name: &str, path: &Path) -> &mut KeyBuilder {
+        let identity = fs::canonicalize(path)
+            .and_then(|path| {
+                let metadata = fs::metadata(&path)?;
+                Ok(format!(
+                    "{} {} {:?}",
+                    path.display(),
+                    metadata.len(),
+                    metadata.modified().ok()
+                ))
+            })
+            .unwrap_or_default();
+        self.add(name, identity)
+    }
+
+    /// Add the names and contents of all files below `dir`, except for hidden ones and
+    /// build output in `target` directories.
+    pub fn add_dir(&mut self, name: &str, dir: &Path) -> &mut KeyBuilder {
+        let mut files = Vec::new();
+        collect_files(dir, &mut files);
+        files.sort();
+        for file in files {
+            let relative = file.strip_prefix(dir).unwrap_or(&file);
+            self.add(name, relative.to_string_lossy().as_bytes())
+                .add(name, fs::read(&file).unwrap_or_default());
+        }
+        self
+    }
+
+    pub fn finish(self) -> String {
+        self.0.result().iter().fold(String::new(), |mut hex, byte| {
+            write!(hex, "{:02x}", byte).ok();
+            hex
+        })
+    }
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let name = entry.file_name();
+        if name.to_string_lossy().starts_with('.') || name == "target" {
+            continue;
+        }
+        match entry.file_type() {
+            Ok(t) if t.is_dir() => collect_files(&entry.path(), files),
+            Ok(t) if t.is_file() => files.push(entry.path()),
+            _ => {}
+        }
+    }
+}
+
+/// Remove all cached results of the book at `dir`.
+///
+/// Returns the path of the removed directory, or `None` if there were no cached results.
+pub fn clean(dir: &Path) -> Result<Option<PathBuf>> {
+    let cache_dir = dir.join(CACHE_DIR);
+    if !cache_dir.is_dir() {
+        return Ok(None);
+    }
+    fs::remove_dir_all(&cache_dir).map_err(|e| {
+        Error::from(e).chain_err(|| format!("Could not remove cache at '{}'", cache_dir.display()))
+    })?;
+    Ok(Some(cache_dir))
+}
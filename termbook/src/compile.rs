@@ -0,0 +1,143 @@
+use crate::{cache::KeyBuilder, config::RustConfig};
+
+use mdbook::errors::{Error, Result};
+use tempfile::TempDir;
+use toml::value::{Table, Value};
+
+use std::{
+    fs::{create_dir_all, write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// The name of the code block type to compile with the Rust compiler.
+pub(crate) const RUST: &str = "rust";
+
+/// A crate in a temporary directory to compile `rust` code blocks with.
+///
+/// It is reused for all code blocks, so dependencies are only built once.
+pub(crate) struct RustCrate {
+    dir: TempDir,
+    use_cargo: bool,
+    edition: String,
+}
+
+impl RustCrate {
+    pub fn new(config: &RustConfig, book_root: &Path) -> Result<RustCrate> {
+        let dir = tempfile::Builder::new().prefix("termbook-rust").tempdir()?;
+        create_dir_all(dir.path().join("src"))?;
+        let use_cargo = !config.dependencies.is_empty();
+        if use_cargo {
+            write(dir.path().join("Cargo.toml"), manifest(config, book_root)?)?;
+        }
+        Ok(RustCrate {
+            dir,
+            use_cargo,
+            edition: config.edition.clone(),
+        })
+    }
+
+    /// Compile `code` into an executable and return its path.
+    ///
+    /// Code without a `main` function is placed into one.
+    pub fn compile(&self, code: &str) -> Result<PathBuf> {
+        let source = if code.contains("fn main") {
+            code.to_owned()
+        } else {
+            format!("fn main() {{\n{}}}\n", code)
+        };
+        let root = self.dir.path();
+        write(root.join("src").join("main.rs"), source)?;
+
+        let (mut command, executable) = if self.use_cargo {
+            // The target directory is explicit, as `CARGO_TARGET_DIR` would move the executable.
+            let target_dir = root.join("target");
+            let mut command = Command::new("cargo");
+            command
+                .args(["build", "--quiet", "--color", "never", "--target-dir"])
+                .arg(&target_dir);
+            (command, target_dir.join("debug").join("block"))
+        } else {
+            let executable = root.join("block");
+            let mut command = Command::new("rustc");
+            command
+                .args(["--color", "never", "--crate-name", "block", "--edition"])
+                .arg(&self.edition)
+                .arg("-o")
+                .arg(&executable)
+                .arg(Path::new("src").join("main.rs"));
+            (command, executable)
+        };
+        // Compile from within the crate to keep temporary paths out of error messages.
+        let output = command
+            .current_dir(root)
+            .output().map_err(|e| {
+            Error::from(format!(
+                "Failed to run the compiler for a '{}' code block with error: {}",
+                RUST, e
+            ))
+        })?;
+        if !output.status.success() {
+            return Err(format!(
+                "Failed to compile '{}' code block:\n{}",
+                RUST,
+                String::from_utf8_lossy(&output.stderr)
+            ).into());
+        }
+        Ok(executable)
+    }
+}
+
+/// Add the versions of the compilers and the sources of path dependencies to `key`,
+/// as compiled programs change along with them.
+pub(crate) fn add_to_key(config: &RustConfig, book_root: &Path, key: &mut KeyBuilder) {
+    let compilers: &[&str] = if config.dependencies.is_empty() {
+        &["rustc"]
+    } else {
+        &["rustc", "cargo"]
+    };
+    for compiler in compilers {
+        let version = Command::new(compiler)
+            .arg("-vV")
+            .output()
+            .map(|output| output.stdout)
+            .unwrap_or_default();
+        key.add(compiler, version);
+    }
+    for dependency in config.dependencies.values() {
+        if let Some(Value::String(path)) = dependency.as_table().and_then(|d| d.get("path")) {
+            key.add_dir("path-dependency", &book_root.join(path));
+        }
+    }
+}
+
+/// Create a `Cargo.toml` file with the configured dependencies, resolving their paths
+/// relative to the `book_root`.
+fn manifest(config: &RustConfig, book_root: &Path) -> Result<String> {
+    let mut dependencies = config.dependencies.clone();
+    for (_, dependency) in dependencies.iter_mut() {
+        if let Some(Value::String(ref mut path)) =
+            dependency.as_table_mut().and_then(|d| d.get_mut("path"))
+        {
+            let absolute = book_root.join(&*path).canonicalize().map_err(|e| {
+                Error::from(e).chain_err(|| {
+                    format!(
+                        "Path dependency at '{}' could not be found relative to the book",
+                        path
+                    )
+                })
+            })?;
+            *path = absolute.to_string_lossy().into_owned();
+        }
+    }
+
+    let mut package = Table::new();
+    package.insert("name".into(), "block".into());
+    package.insert("version".into(), "0.0.0".into());
+    package.insert("edition".into(), config.edition.clone().into());
+    let mut manifest = Table::new();
+    manifest.insert("package".into(), Value::Table(package));
+    manifest.insert("dependencies".into(), Value::Table(dependencies));
+    manifest.insert("workspace".into(), Value::Table(Table::new()));
+    toml::to_string(&Value::Table(manifest)).map_err(|e| format!("{}", e).into())
+}
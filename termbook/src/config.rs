@@ -0,0 +1,467 @@
+use crate::{
+    exclude_chapter,
+    preprocess::PREPROCESSOR_NAME,
+    redact::{Preset, Rule, DEFAULT_REPLACEMENT},
+};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use mdbook::{book::Chapter, errors::Result};
+use regex::bytes::Regex;
+use toml::value::{Table, Value};
+
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+
+/// The configuration of the `RunCodeBlocks` preprocessor, as provided by the
+/// `[preprocessor.run-code-blocks]` table of the `book.toml` file.
+#[derive(Default, Clone)]
+pub(crate) struct Config {
+    /// The working directory of executed programs, relative to the book root.
+    pub cwd: Option<PathBuf>,
+    /// Environment variables to set for executed programs.
+    pub env: BTreeMap<String, String>,
+    /// If true, executed programs only see the variables in `env`.
+    pub clear_env: bool,
+    /// The time after which executed programs are killed.
+    pub timeout: Option<Duration>,
+    /// Command lines to execute instead of the program named by the code block, by program name.
+    pub interpreters: BTreeMap<String, Vec<String>>,
+    /// How to compile `rust` code blocks.
+    pub rust: RustConfig,
+    /// The chapters to execute programs in a temporary directory for.
+    pub temp_dirs: TempDirs,
+    /// The size of the pseudo-terminal to execute programs in, if they should be.
+    pub tty: Option<TtySize>,
+    /// If true, escape sequences in the output of programs are converted for the renderer.
+    pub ansi: bool,
+    /// If true, the output of all executed blocks is compared with a snapshot file.
+    pub snapshots: bool,
+    /// The directory holding snapshot files relative to the book root, if not `snapshots`.
+    pub snapshot_dir: Option<PathBuf>,
+    /// Rules replacing text in the output of programs, in the order they are applied.
+    pub redact: Vec<Rule>,
+    /// Built-in rules replacing paths in the output of programs.
+    pub redact_presets: Vec<Preset>,
+    /// The summary of collapsed output, if not `Output`.
+    pub collapse_summary: Option<String>,
+    /// The prefix of lines in code blocks which are executed, but not shown.
+    pub hidden_line_prefix: Option<String>,
+    /// If true, code blocks with unknown tags are an error.
+    pub strict_tags: bool,
+    /// If true, the output of executed programs is cached and reused in later builds.
+    pub cache: bool,
+    /// Tags which are not an error in strict mode, as they are used by other tools.
+    pub allowed_tags: Vec<String>,
+}
+
+/// The size of a pseudo-terminal.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct TtySize {
+    pub cols: u16,
+    pub rows: u16,
+}
+
+impl Default for TtySize {
+    fn default() -> Self {
+        TtySize { cols: 80, rows: 24 }
+    }
+}
+
+impl TtySize {
+    /// Parse sizes like `120x40`, which is 120 columns and 40 rows.
+    pub fn parse(input: &str) -> std::result::Result<TtySize, String> {
+        let mut cr = input.trim().splitn(2, 'x');
+        match (
+            cr.next().and_then(|c| c.parse().ok()),
+            cr.next().and_then(|r| r.parse().ok()),
+        ) {
+            (Some(cols), Some(rows)) if cols > 0 && rows > 0 => Ok(TtySize { cols, rows }),
+            _ => Err(format!(
+                "'{}' is not a size like '80x24', which is columns by rows",
+                input
+            )),
+        }
+    }
+}
+
+/// The chapters whose programs run in a fresh temporary directory, as provided by the
+/// `temp-dir` key.
+#[derive(Clone, Default)]
+pub(crate) enum TempDirs {
+    #[default]
+    None,
+    All,
+    /// Chapters whose name or section number matches any of the globs.
+    Matching(GlobSet),
+}
+
+impl TempDirs {
+    pub fn is_enabled_for(&self, chapter: &Chapter) -> bool {
+        match *self {
+            TempDirs::None => false,
+            TempDirs::All => true,
+            TempDirs::Matching(ref globs) => !exclude_chapter(globs, chapter),
+        }
+    }
+
+    fn from_value(value: &Value) -> Result<TempDirs> {
+        Ok(match *value {
+            Value::Boolean(true) => TempDirs::All,
+            Value::Boolean(false) => TempDirs::None,
+            Value::Array(ref globs) if globs.is_empty() => TempDirs::None,
+            Value::Array(ref globs) => {
+                let mut builder = GlobSetBuilder::new();
+                for glob in globs {
+                    let glob = as_str("temp-dir", glob)?;
+                    builder.add(Glob::new(glob).map_err(|e| {
+                        format!(
+                            "'temp-dir' in [preprocessor.{}] contains invalid glob '{}': {}",
+                            PREPROCESSOR_NAME, glob, e
+                        )
+                    })?);
+                }
+                TempDirs::Matching(builder.build().map_err(|e| format!("{}", e))?)
+            }
+            _ => {
+                return Err(format!(
+                    "'temp-dir' in [preprocessor.{}] must be a boolean or a list of chapter globs, got '{}'.",
+                    PREPROCESSOR_NAME, value
+                ).into())
+            }
+        })
+    }
+}
+
+/// The configuration for compiling `rust` code blocks, as provided by the
+/// `[preprocessor.run-code-blocks.rust]` table.
+#[derive(Clone)]
+pub(crate) struct RustConfig {
+    /// The Rust edition to compile code with.
+    pub edition: String,
+    /// Dependencies in the format of a `Cargo.toml` file. If set, code is compiled with `cargo`
+    /// instead of `rustc`.
+    pub dependencies: Table,
+}
+
+impl Default for RustConfig {
+    fn default() -> Self {
+        RustConfig {
+            edition: "2018".into(),
+            dependencies: Table::new(),
+        }
+    }
+}
+
+impl RustConfig {
+    fn from_table(table: &Table) -> Result<RustConfig> {
+        let mut config = RustConfig::default();
+        if let Some(edition) = table.get("edition") {
+            config.edition = as_str("rust.edition", edition)?.to_owned();
+        }
+        match table.get("dependencies") {
+            Some(Value::Table(dependencies)) => config.dependencies = dependencies.clone(),
+            Some(value) => {
+                return Err(format!(
+                    "'rust.dependencies' in [preprocessor.{}] must be a table, got '{}'.",
+                    PREPROCESSOR_NAME, value
+                ).into())
+            }
+            None => {}
+        }
+        Ok(config)
+    }
+}
+
+impl Config {
+    pub fn from_table(table: &Table) -> Result<Config> {
+        Ok(Config {
+            cwd: table
+                .get("cwd")
+                .map(|v| as_str("cwd", v))
+                .transpose()?
+                .map(PathBuf::from),
+            redact: match table.get("redact") {
+                Some(Value::Array(rules)) => rules
+                    .iter()
+                    .enumerate()
+                    .map(|(index, rule)| redaction_rule(&format!("redact[{}]", index), rule))
+                    .collect::<Result<_>>()?,
+                Some(value) => {
+                    return Err(format!(
+                        "'redact' in [preprocessor.{}] must be a list of tables with a 'regex' and a 'replacement', got '{}'.",
+                        PREPROCESSOR_NAME, value
+                    ).into())
+                }
+                None => Vec::new(),
+            },
+            redact_presets: match table.get("redact-presets") {
+                Some(Value::Array(presets)) => presets
+                    .iter()
+                    .map(|preset| {
+                        let name = as_str("redact-presets", preset)?;
+                        Preset::from_name(name).ok_or_else(|| {
+                            format!(
+                                "'redact-presets' in [preprocessor.{}] contains unknown preset '{}', expected one of {}.",
+                                PREPROCESSOR_NAME,
+                                name,
+                                preset_names()
+                            ).into()
+                        })
+                    })
+                    .collect::<Result<_>>()?,
+                Some(value) => {
+                    return Err(format!(
+                        "'redact-presets' in [preprocessor.{}] must be a list of preset names, got '{}'.",
+                        PREPROCESSOR_NAME, value
+                    ).into())
+                }
+                None => Vec::new(),
+            },
+            env: match table.get("env") {
+                Some(Value::Table(vars)) => vars
+                    .iter()
+                    .map(|(name, value)| {
+                        as_str(&format!("env.{}", name), value)
+                            .map(|value| (name.to_owned(), value.to_owned()))
+                    })
+                    .collect::<Result<_>>()?,
+                Some(value) => {
+                    return Err(format!(
+                        "'env' in [preprocessor.{}] must be a table, got '{}'.",
+                        PREPROCESSOR_NAME, value
+                    ).into())
+                }
+                None => BTreeMap::new(),
+            },
+            clear_env: table
+                .get("clear-env")
+                .map(|v| as_bool("clear-env", v))
+                .transpose()?
+                .unwrap_or(false),
+            timeout: match table.get("timeout") {
+                Some(Value::Integer(secs)) if *secs >= 0 => Some(Duration::from_secs(*secs as u64)),
+                Some(Value::String(duration)) => Some(parse_duration(duration).map_err(|e| {
+                    format!(
+                        "'timeout' in [preprocessor.{}] is invalid: {}",
+                        PREPROCESSOR_NAME, e
+                    )
+                })?),
+                Some(value) => {
+                    return Err(format!(
+                        "'timeout' in [preprocessor.{}] must be a duration like \"30s\", got '{}'.",
+                        PREPROCESSOR_NAME, value
+                    ).into())
+                }
+                None => None,
+            },
+            interpreters: match table.get("interpreters") {
+                Some(Value::Table(interpreters)) => interpreters
+                    .iter()
+                    .map(|(name, value)| {
+                        as_command_line(&format!("interpreters.{}", name), value)
+                            .map(|command_line| (name.to_owned(), command_line))
+                    })
+                    .collect::<Result<_>>()?,
+                Some(value) => {
+                    return Err(format!(
+                        "'interpreters' in [preprocessor.{}] must be a table, got '{}'.",
+                        PREPROCESSOR_NAME, value
+                    ).into())
+                }
+                None => BTreeMap::new(),
+            },
+            rust: match table.get("rust") {
+                Some(Value::Table(rust)) => RustConfig::from_table(rust)?,
+                Some(value) => {
+                    return Err(format!(
+                        "'rust' in [preprocessor.{}] must be a table, got '{}'.",
+                        PREPROCESSOR_NAME, value
+                    ).into())
+                }
+                None => RustConfig::default(),
+            },
+            tty: match table.get("tty") {
+                Some(Value::Boolean(true)) => Some(TtySize::default()),
+                Some(Value::Boolean(false)) | None => None,
+                Some(Value::String(size)) => Some(TtySize::parse(size).map_err(|e| {
+                    format!("'tty' in [preprocessor.{}] is invalid: {}", PREPROCESSOR_NAME, e)
+                })?),
+                Some(value) => {
+                    return Err(format!(
+                        "'tty' in [preprocessor.{}] must be a boolean or a size like \"80x24\", got '{}'.",
+                        PREPROCESSOR_NAME, value
+                    ).into())
+                }
+            },
+            ansi: table
+                .get("ansi")
+                .map(|v| as_bool("ansi", v))
+                .transpose()?
+                .unwrap_or(false),
+            temp_dirs: table
+                .get("temp-dir")
+                .map(TempDirs::from_value)
+                .transpose()?
+                .unwrap_or_default(),
+            snapshots: table
+                .get("snapshots")
+                .map(|v| as_bool("snapshots", v))
+                .transpose()?
+                .unwrap_or(false),
+            snapshot_dir: table
+                .get("snapshot-dir")
+                .map(|v| as_str("snapshot-dir", v))
+                .transpose()?
+                .map(PathBuf::from),
+            collapse_summary: table
+                .get("collapse-summary")
+                .map(|v| as_str("collapse-summary", v))
+                .transpose()?
+                .map(ToOwned::to_owned),
+            hidden_line_prefix: match table.get("hidden-line-prefix") {
+                Some(value) => match as_str("hidden-line-prefix", value)? {
+                    prefix if prefix.trim().is_empty() => {
+                        return Err(format!(
+                            "'hidden-line-prefix' in [preprocessor.{}] must not be blank.",
+                            PREPROCESSOR_NAME
+                        ).into())
+                    }
+                    prefix => Some(prefix.to_owned()),
+                },
+                None => None,
+            },
+            strict_tags: table
+                .get("strict-tags")
+                .map(|v| as_bool("strict-tags", v))
+                .transpose()?
+                .unwrap_or(false),
+            cache: table
+                .get("cache")
+                .map(|v| as_bool("cache", v))
+                .transpose()?
+                .unwrap_or(false),
+            allowed_tags: match table.get("allowed-tags") {
+                Some(Value::Array(tags)) => tags
+                    .iter()
+                    .map(|tag| as_str("allowed-tags", tag).map(ToOwned::to_owned))
+                    .collect::<Result<_>>()?,
+                Some(value) => {
+                    return Err(format!(
+                        "'allowed-tags' in [preprocessor.{}] must be a list of tag names, got '{}'.",
+                        PREPROCESSOR_NAME, value
+                    ).into())
+                }
+                None => Vec::new(),
+            },
+        })
+    }
+}
+
+fn redaction_rule(key: &str, value: &Value) -> Result<Rule> {
+    let rule = value.as_table().ok_or_else(|| {
+        format!(
+            "'{}' in [preprocessor.{}] must be a table with a 'regex' and a 'replacement', got '{}'.",
+            key, PREPROCESSOR_NAME, value
+        )
+    })?;
+    let regex = match rule.get("regex") {
+        Some(regex) => as_str(&format!("{}.regex", key), regex)?,
+        None => {
+            return Err(format!(
+                "'{}' in [preprocessor.{}] needs a 'regex'.",
+                key, PREPROCESSOR_NAME
+            ).into())
+        }
+    };
+    Ok(Rule {
+        name: rule
+            .get("name")
+            .map(|v| as_str(&format!("{}.name", key), v))
+            .transpose()?
+            .map(ToOwned::to_owned),
+        regex: Regex::new(regex).map_err(|e| {
+            format!(
+                "'{}.regex' in [preprocessor.{}] is invalid: {}",
+                key, PREPROCESSOR_NAME, e
+            )
+        })?,
+        replacement: rule
+            .get("replacement")
+            .map(|v| as_str(&format!("{}.replacement", key), v))
+            .transpose()?
+            .unwrap_or(DEFAULT_REPLACEMENT)
+            .to_owned(),
+    })
+}
+
+/// The names of all redaction presets, for use in messages.
+pub(crate) fn preset_names() -> String {
+    Preset::ALL
+        .iter()
+        .map(|preset| format!("'{}'", preset.name()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn as_str<'a>(key: &str, value: &'a Value) -> Result<&'a str> {
+    value.as_str().ok_or_else(|| {
+        format!(
+            "'{}' in [preprocessor.{}] must be a string, got '{}'.",
+            key, PREPROCESSOR_NAME, value
+        ).into()
+    })
+}
+
+fn as_command_line(key: &str, value: &Value) -> Result<Vec<String>> {
+    let command_line = match *value {
+        Value::String(ref program) => vec![program.to_owned()],
+        Value::Array(ref args) => args
+            .iter()
+            .map(|arg| as_str(key, arg).map(ToOwned::to_owned))
+            .collect::<Result<_>>()?,
+        _ => Vec::new(),
+    };
+    if command_line.is_empty() {
+        return Err(format!(
+            "'{}' in [preprocessor.{}] must be a program or a non-empty list of a program and its arguments, got '{}'.",
+            key, PREPROCESSOR_NAME, value
+        ).into());
+    }
+    Ok(command_line)
+}
+
+fn as_bool(key: &str, value: &Value) -> Result<bool> {
+    value.as_bool().ok_or_else(|| {
+        format!(
+            "'{}' in [preprocessor.{}] must be a boolean, got '{}'.",
+            key, PREPROCESSOR_NAME, value
+        ).into()
+    })
+}
+
+/// Parse durations like `500ms`, `30s`, `2m` or `1h`. Plain numbers are seconds.
+pub(crate) fn parse_duration(input: &str) -> std::result::Result<Duration, String> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (amount, unit) = input.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("'{}' does not start with a number", input))?;
+    let secs_per_unit = match unit {
+        "ms" => return Ok(Duration::from_millis(amount)),
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => {
+            return Err(format!(
+                "unknown unit '{}', expected one of 'ms', 's', 'm' or 'h'",
+                unit
+            ))
+        }
+    };
+    amount
+        .checked_mul(secs_per_unit)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("'{}' is too long", input))
+}
@@ -0,0 +1,120 @@
+use mdbook::{book::Chapter, errors::Error};
+
+use std::{error, fmt, ops::Range, path::PathBuf};
+
+/// A problem encountered while preprocessing a single code block.
+#[derive(Debug)]
+pub struct Diagnostic {
+    /// The name of the chapter containing the code block.
+    pub chapter: String,
+    /// The chapter's file, relative to the books source directory.
+    pub file: PathBuf,
+    /// The 1-based index of the code block within its chapter.
+    pub block: usize,
+    /// The 1-based line of the offending tag, or of the code block fence.
+    pub line: usize,
+    /// The 1-based column of the offending tag, or of the code block fence.
+    pub column: usize,
+    /// The source line containing the offending tag, with the tag underlined.
+    pub excerpt: String,
+    /// What went wrong.
+    pub cause: Error,
+}
+
+impl Diagnostic {
+    /// Create a new instance for the code `block` in `chapter`, locating the cause of the problem
+    /// at the byte range `span` of the chapter's content.
+    pub fn new(chapter: &Chapter, block: usize, span: Range<usize>, cause: Error) -> Diagnostic {
+        let content = &chapter.content;
+        let line_start = content[..span.start].rfind('\n').map_or(0, |p| p + 1);
+        let line_end = content[span.start..]
+            .find('\n')
+            .map_or(content.len(), |p| span.start + p);
+        let line = content[..span.start].matches('\n').count() + 1;
+        let column = content[line_start..span.start].chars().count() + 1;
+
+        let source_line = content[line_start..line_end].trim_end();
+        let underline_len = content[span.start..span.end.min(line_end)]
+            .chars()
+            .count()
+            .max(1);
+        let gutter = " ".repeat(line.to_string().len());
+        let excerpt = format!(
+            "{gutter} |\n{line} | {source}\n{gutter} | {indent}{underline}",
+            gutter = gutter,
+            line = line,
+            source = source_line,
+            indent = content[line_start..span.start]
+                .chars()
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect::<String>(),
+            underline = "^".repeat(underline_len),
+        );
+
+        Diagnostic {
+            chapter: chapter.name.clone(),
+            file: chapter.path.clone(),
+            block,
+            line,
+            column,
+            excerpt,
+            cause,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: code block {} of chapter '{}'",
+            self.file.display(),
+            self.line,
+            self.column,
+            self.block,
+            self.chapter
+        )
+    }
+}
+
+/// All problems encountered while preprocessing a book, in book order.
+///
+/// It is passed as cause of the error returned by the `RunCodeBlocks` preprocessor.
+#[derive(Debug, Default)]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+impl Diagnostics {
+    /// Find the diagnostics within the chain of causes of the given `error`, if there are any.
+    pub fn find(error: &Error) -> Option<&Diagnostics> {
+        let mut source = error::Error::source(error);
+        while let Some(err) = source {
+            if let Some(diagnostics) = err.downcast_ref::<Diagnostics>() {
+                return Some(diagnostics);
+            }
+            source = err.source();
+        }
+        None
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.0.iter()
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, diagnostic) in self.0.iter().enumerate() {
+            if index != 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", diagnostic)?;
+            for cause in diagnostic.cause.iter() {
+                write!(f, ": {}", cause)?;
+            }
+            write!(f, "\n{}", diagnostic.excerpt)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for Diagnostics {}
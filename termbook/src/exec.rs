@@ -0,0 +1,239 @@
+use crate::{config::TtySize, preprocess::PREPROCESSOR_NAME};
+
+use tempfile::TempDir;
+
+use std::{
+    env,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    thread::{self, sleep, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// The environment variable holding the path to the temporary directory programs are executed in.
+pub(crate) const TEMP_DIR_VAR: &str = "TERMBOOK_TEMP_DIR";
+
+/// The file `program` refers to, looked up in the `path` variable if it is just a name,
+/// or in the `PATH` of this process if that is not set.
+pub(crate) fn find_program(program: &str, path: Option<&str>) -> Option<PathBuf> {
+    if Path::new(program).components().count() > 1 {
+        return Some(PathBuf::from(program));
+    }
+    let path = path.map(Into::into).or_else(|| env::var_os("PATH"))?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|candidate| candidate.is_file())
+}
+
+/// A temporary directory to execute programs in, which is removed when dropped unless it
+/// should be kept for inspection.
+pub(crate) struct WorkDir {
+    dir: Option<TempDir>,
+    keep: bool,
+}
+
+impl WorkDir {
+    pub fn new(keep: bool) -> io::Result<WorkDir> {
+        Ok(WorkDir {
+            dir: Some(tempfile::Builder::new().prefix("termbook-").tempdir()?),
+            keep,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.as_ref().expect("only taken when dropped").path()
+    }
+
+    /// Make `command` run in this directory, and tell it where it is.
+    pub fn apply(&self, command: &mut Command) {
+        command.current_dir(self.path()).env(TEMP_DIR_VAR, self.path());
+    }
+}
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        if let Some(dir) = self.dir.take() {
+            if self.keep {
+                eprintln!(
+                    "{}: Kept temporary directory at '{}'.",
+                    PREPROCESSOR_NAME,
+                    dir.into_path().display()
+                );
+            }
+        }
+    }
+}
+
+/// How often to check whether a program with a timeout has exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The result of running a program to completion, or until it timed out.
+pub(crate) struct Completion {
+    /// The exit code, or `None` if the program was killed after timing out.
+    pub status: Option<i32>,
+    /// Everything written to stdout, which is partial if the program timed out.
+    pub stdout: Vec<u8>,
+    /// Everything written to stderr, which is partial if the program timed out.
+    pub stderr: Vec<u8>,
+}
+
+/// How to capture the output of a program.
+#[derive(Clone, Copy)]
+pub(crate) enum Capture {
+    /// Through separate pipes for stdout and stderr.
+    Separate,
+    /// Through one pipe shared by stdout and stderr, which keeps the order of their output.
+    Interleaved,
+    /// Through a pseudo-terminal of the given size shared by stdout and stderr, which makes
+    /// the program behave like it does for users.
+    Tty(TtySize),
+}
+
+/// Configure `command` to have piped standard streams, and to run in its own process group
+/// so that it can be killed along with all processes it started.
+pub(crate) fn configure(command: &mut Command) {
+    command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+}
+
+/// Spawn `command` in its own process group, feed it `input` through stdin and collect its output.
+///
+/// If `timeout` is set and elapses before the program and all processes holding on to its
+/// output exit, its entire process group is killed.
+/// Unless the output is `capture`d separately, all of it is returned as stdout.
+pub(crate) fn run(
+    command: &mut Command,
+    input: &[u8],
+    timeout: Option<Duration>,
+    capture: Capture,
+) -> io::Result<Completion> {
+    configure(command);
+    let shared_pipe: Option<Box<dyn Read + Send>> = match capture {
+        Capture::Separate => None,
+        Capture::Interleaved => {
+            let (reader, writer) = io::pipe()?;
+            command.stdout(writer.try_clone()?).stderr(writer);
+            Some(Box::new(reader))
+        }
+        #[cfg(unix)]
+        Capture::Tty(size) => {
+            let (master, slave) = crate::pty::open(size)?;
+            command.stdout(slave.try_clone()?).stderr(slave);
+            Some(Box::new(master))
+        }
+        #[cfg(not(unix))]
+        Capture::Tty(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "pseudo-terminals are only supported on unix",
+            ))
+        }
+    };
+    let spawned = command.spawn();
+    if shared_pipe.is_some() {
+        // The command holds on to the writing end, which would prevent reading to the end.
+        command.stdout(Stdio::null()).stderr(Stdio::null());
+    }
+    let mut child = spawned?;
+
+    let stdin = child.stdin.take().expect("stdin to be configured");
+    let input = input.to_owned();
+    let writer = thread::spawn(move || {
+        let mut stdin = stdin;
+        // The program may exit without reading all of its input, which is fine.
+        stdin.write_all(&input).ok();
+    });
+    let stdout = Arc::new(Mutex::new(Vec::new()));
+    let stderr = Arc::new(Mutex::new(Vec::new()));
+    let readers = match shared_pipe {
+        Some(reader) => vec![collect(reader, &stdout)],
+        None => vec![
+            collect(child.stdout.take().expect("stdout to be configured"), &stdout),
+            collect(child.stderr.take().expect("stderr to be configured"), &stderr),
+        ],
+    };
+
+    // A timeout too large to ever elapse is the same as none.
+    let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+    let mut status = match deadline {
+        None => Some(exit_code(child.wait()?)),
+        Some(deadline) => {
+            loop {
+                if let Some(status) = child.try_wait()? {
+                    break Some(exit_code(status));
+                }
+                if Instant::now() >= deadline {
+                    kill_process_group(child.id());
+                    child.kill().ok();
+                    child.wait()?;
+                    break None;
+                }
+                sleep(POLL_INTERVAL);
+            }
+        }
+    };
+
+    // Processes started in the background can keep the pipes open after the program exited,
+    // so they are killed as well once the deadline passes.
+    if let Some(deadline) = deadline {
+        while !(writer.is_finished() && readers.iter().all(JoinHandle::is_finished)) {
+            if Instant::now() >= deadline {
+                kill_process_group(child.id());
+                status = None;
+                break;
+            }
+            sleep(POLL_INTERVAL);
+        }
+    }
+    writer.join().ok();
+    for reader in readers {
+        reader.join().ok();
+    }
+    let take = |buf: Arc<Mutex<Vec<u8>>>| std::mem::take(&mut *buf.lock().expect("no poisoning"));
+    Ok(Completion {
+        status,
+        stdout: take(stdout),
+        stderr: take(stderr),
+    })
+}
+
+fn collect<R>(mut stream: R, buf: &Arc<Mutex<Vec<u8>>>) -> JoinHandle<()>
+where
+    R: Read + Send + 'static,
+{
+    let buf = Arc::clone(buf);
+    thread::spawn(move || {
+        let mut chunk = [0u8; 4096];
+        while let Ok(n) = stream.read(&mut chunk) {
+            if n == 0 {
+                break;
+            }
+            buf.lock().expect("no poisoning").extend_from_slice(&chunk[..n]);
+        }
+    })
+}
+
+/// The exit code of a program, where termination by a signal counts as failure.
+pub(crate) fn exit_code(status: ExitStatus) -> i32 {
+    status.code().unwrap_or(1)
+}
+
+#[cfg(unix)]
+pub(crate) fn kill_process_group(pid: u32) {
+    // The child is the leader of its own process group, whose id is the child's pid.
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn kill_process_group(_pid: u32) {}
@@ -0,0 +1,434 @@
+use atty::{self, Stream};
+use regex::Regex;
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fmt::Write,
+};
+
+/// The amount of unchanged lines to show around changes in a diff.
+const CONTEXT_LINES: usize = 3;
+
+/// A line of expected output which skips any amount of actual lines.
+const ANY_LINES: &str = "...";
+
+/// If `actual` output does not match the `expected` output, return a unified diff
+/// between both, where the `expected` output is called `name`.
+///
+/// The expected output may contain placeholders:
+/// * `[..]` matches any text within a line.
+/// * `...` on a line of its own matches any amount of lines.
+/// * `[name:regex]` matches the regular expression, and `[name]` matches the text
+///   it matched in any following line. Names start with a letter or `_`.
+/// * `[[` matches a `[`, which is how placeholders are written literally.
+///
+/// Brackets which don't form a valid placeholder match themselves.
+/// The diff is colored if it is printed to a terminal.
+pub(crate) fn mismatch(name: &str, expected: &str, actual: &str) -> Option<String> {
+    if expected == actual {
+        return None;
+    }
+    let patterns: Vec<_> = expected.lines().map(Pattern::parse).collect();
+    let actual_lines: Vec<_> = actual.lines().collect();
+    if Matcher::new(&patterns, &actual_lines).matches(0, 0, &mut Bindings::new()) {
+        return None;
+    }
+    Some(unified_diff(
+        name,
+        &patterns,
+        &actual_lines,
+        atty::is(Stream::Stderr),
+    ))
+}
+
+/// The text captured by placeholders, by their name.
+type Bindings = HashMap<String, String>;
+
+/// A line of expected output.
+enum Pattern<'a> {
+    AnyLines,
+    Line {
+        text: &'a str,
+        segments: Vec<Segment<'a>>,
+        /// The regex matching the line as long as none of its placeholders were captured before.
+        regex: Regex,
+        /// The names of the placeholders captured by `regex`.
+        captured: Vec<&'a str>,
+    },
+}
+
+enum Segment<'a> {
+    Text(&'a str),
+    AnyText,
+    Capture { name: &'a str, regex: &'a str },
+    Reference(&'a str),
+}
+
+impl<'a> Pattern<'a> {
+    fn parse(line: &'a str) -> Pattern<'a> {
+        if line.trim_end() == ANY_LINES {
+            return Pattern::AnyLines;
+        }
+        let mut segments = Vec::new();
+        let mut rest = line;
+        while let Some(start) = rest.find('[') {
+            let (segment, len) = placeholder(&rest[start + 1..]);
+            if start > 0 {
+                segments.push(Segment::Text(&rest[..start]));
+            }
+            segments.push(segment);
+            rest = &rest[start + 1 + len..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest));
+        }
+        let (regex, captured) = line_regex(&segments, &Bindings::new());
+        match Regex::new(&regex) {
+            Ok(regex) => Pattern::Line {
+                text: line,
+                segments,
+                regex,
+                captured,
+            },
+            // Placeholders can still clash, like regexes with groups named like other placeholders.
+            Err(_) => Pattern::literal(line),
+        }
+    }
+
+    /// A line matching only itself.
+    fn literal(line: &'a str) -> Pattern<'a> {
+        let segments = vec![Segment::Text(line)];
+        let (regex, captured) = line_regex(&segments, &Bindings::new());
+        Pattern::Line {
+            text: line,
+            segments,
+            regex: Regex::new(&regex).expect("escaped text to be a valid regex"),
+            captured,
+        }
+    }
+
+    /// Whether the `actual` line matches, given the text of previous captures in `bindings`,
+    /// which receives the captures of this line.
+    ///
+    /// Regexes for lines with placeholders captured before are kept in `regexes` by their source.
+    fn matches_line(
+        &self,
+        actual: &str,
+        bindings: &mut Bindings,
+        regexes: &mut HashMap<String, Regex>,
+    ) -> bool {
+        let (segments, regex, captured) = match *self {
+            Pattern::AnyLines => return false,
+            Pattern::Line {
+                ref segments,
+                ref regex,
+                ref captured,
+                ..
+            } => (segments, regex, captured),
+        };
+        let is_bound = |segment: &Segment| match *segment {
+            Segment::Reference(name) | Segment::Capture { name, .. } => bindings.contains_key(name),
+            Segment::Text(_) | Segment::AnyText => false,
+        };
+        let bound_regex;
+        let (regex, captured) = if segments.iter().any(is_bound) {
+            let (source, bound_captured) = line_regex(segments, bindings);
+            bound_regex = match regexes.entry(source) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => match Regex::new(entry.key()) {
+                    Ok(regex) => entry.insert(regex),
+                    Err(_) => return false,
+                },
+            };
+            (&*bound_regex, bound_captured)
+        } else {
+            (regex, captured.clone())
+        };
+        let captures = match regex.captures(actual) {
+            Some(c) => captured
+                .iter()
+                .filter_map(|name| c.name(name).map(|m| (name.to_string(), m.as_str().to_owned())))
+                .collect::<Vec<_>>(),
+            None => return false,
+        };
+        bindings.extend(captures);
+        true
+    }
+}
+
+/// The segment of the placeholder following a `[`, along with the length of its text after
+/// the `[`, or a literal `[` if the text doesn't form a valid placeholder.
+fn placeholder(text: &str) -> (Segment<'_>, usize) {
+    if text.starts_with("..]") {
+        return (Segment::AnyText, "..]".len());
+    }
+    if text.starts_with('[') {
+        return (Segment::Text("["), 1);
+    }
+    let literal = (Segment::Text("["), 0);
+    let name_len = text
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(text.len());
+    let name = &text[..name_len];
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        return literal;
+    }
+    let after_name = &text[name_len..];
+    if after_name.starts_with(']') {
+        return (Segment::Reference(name), name_len + 1);
+    }
+    let after_colon = match after_name.strip_prefix(':') {
+        Some(after_colon) => after_colon,
+        None => return literal,
+    };
+    match closing_bracket(after_colon) {
+        Some(regex_len) if Regex::new(&after_colon[..regex_len]).is_ok() => (
+            Segment::Capture {
+                name,
+                regex: &after_colon[..regex_len],
+            },
+            name_len + 1 + regex_len + 1,
+        ),
+        _ => literal,
+    }
+}
+
+/// Return the source of a regex matching a line made of `segments`, with the text of previous
+/// captures in `bindings`, along with the names of the placeholders it captures.
+fn line_regex<'a>(segments: &[Segment<'a>], bindings: &Bindings) -> (String, Vec<&'a str>) {
+    let mut regex = String::from("^");
+    let mut captured = Vec::new();
+    for segment in segments {
+        match *segment {
+            Segment::Text(text) => regex.push_str(&regex::escape(text)),
+            Segment::AnyText => regex.push_str(".*"),
+            Segment::Reference(name) => match bindings.get(name) {
+                Some(value) => regex.push_str(&regex::escape(value)),
+                None => regex.push_str(&regex::escape(&format!("[{}]", name))),
+            },
+            Segment::Capture { name, regex: pattern } => match bindings.get(name) {
+                Some(value) => regex.push_str(&regex::escape(value)),
+                None if captured.contains(&name) => {
+                    write!(regex, "(?:{})", pattern).ok();
+                }
+                None => {
+                    captured.push(name);
+                    write!(regex, "(?P<{}>{})", name, pattern).ok();
+                }
+            },
+        }
+    }
+    regex.push('$');
+    (regex, captured)
+}
+
+impl<'a> Pattern<'a> {
+    fn text(&self) -> &'a str {
+        match *self {
+            Pattern::AnyLines => ANY_LINES,
+            Pattern::Line { text, .. } => text,
+        }
+    }
+}
+
+/// Return the length of `input` up to the `]` closing a bracket opened before it.
+fn closing_bracket(input: &str) -> Option<usize> {
+    let mut depth = 1;
+    let mut escaped = false;
+    for (index, c) in input.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(index);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Matches lines of actual output with patterns, trying all possible amounts of lines
+/// for each `...`.
+struct Matcher<'p, 'a> {
+    patterns: &'p [Pattern<'a>],
+    actual: &'p [&'p str],
+    /// The captures of failed attempts to match the patterns from index `i` with the lines
+    /// from index `j`, at `i * (actual.len() + 1) + j`. Each attempt is made only once, as the
+    /// outcome only depends on these.
+    failed: Vec<Vec<Bindings>>,
+    regexes: HashMap<String, Regex>,
+}
+
+impl<'p, 'a> Matcher<'p, 'a> {
+    fn new(patterns: &'p [Pattern<'a>], actual: &'p [&'p str]) -> Matcher<'p, 'a> {
+        Matcher {
+            patterns,
+            actual,
+            failed: vec![Vec::new(); (patterns.len() + 1) * (actual.len() + 1)],
+            regexes: HashMap::new(),
+        }
+    }
+
+    /// Whether the patterns from index `i` match the lines from index `j`, given the text of
+    /// previous captures in `bindings`, which receives the captures of the match.
+    fn matches(&mut self, i: usize, j: usize, bindings: &mut Bindings) -> bool {
+        let pattern = match self.patterns.get(i) {
+            Some(pattern) => pattern,
+            None => return j == self.actual.len(),
+        };
+        let cell = i * (self.actual.len() + 1) + j;
+        if self.failed[cell].contains(bindings) {
+            return false;
+        }
+        let is_match = match *pattern {
+            Pattern::AnyLines => (j..=self.actual.len()).any(|next| {
+                let mut attempt_bindings = bindings.clone();
+                let is_match = self.matches(i + 1, next, &mut attempt_bindings);
+                if is_match {
+                    *bindings = attempt_bindings;
+                }
+                is_match
+            }),
+            Pattern::Line { .. } => {
+                let mut attempt_bindings = bindings.clone();
+                let is_match = j < self.actual.len()
+                    && pattern.matches_line(self.actual[j], &mut attempt_bindings, &mut self.regexes)
+                    && self.matches(i + 1, j + 1, &mut attempt_bindings);
+                if is_match {
+                    *bindings = attempt_bindings;
+                }
+                is_match
+            }
+        };
+        if !is_match {
+            self.failed[cell].push(bindings.clone());
+        }
+        is_match
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Change {
+    Same,
+    Removed,
+    Added,
+}
+
+/// Compute the changes turning the `expected` lines into the `actual` ones, using their
+/// longest common subsequence. Lines matching their pattern on their own count as unchanged.
+fn changes<'a>(expected: &[Pattern<'a>], actual: &[&'a str]) -> Vec<(Change, &'a str)> {
+    let (n, m) = (expected.len(), actual.len());
+    let mut regexes = HashMap::new();
+    let same: Vec<Vec<bool>> = expected
+        .iter()
+        .map(|pattern| {
+            actual
+                .iter()
+                .map(|line| pattern.matches_line(line, &mut Bindings::new(), &mut regexes))
+                .collect()
+        })
+        .collect();
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if same[i][j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut changes = Vec::with_capacity(n.max(m));
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && same[i][j] {
+            changes.push((Change::Same, actual[j]));
+            i += 1;
+            j += 1;
+        } else if i < n && (j == m || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            changes.push((Change::Removed, expected[i].text()));
+            i += 1;
+        } else {
+            changes.push((Change::Added, actual[j]));
+            j += 1;
+        }
+    }
+    changes
+}
+
+fn unified_diff(name: &str, expected: &[Pattern], actual: &[&str], color: bool) -> String {
+    let changes = changes(expected, actual);
+
+    let paint = |line: String, code: &str| {
+        if color {
+            format!("\x1b[{}m{}\x1b[0m", code, line)
+        } else {
+            line
+        }
+    };
+    let mut diff = String::new();
+    writeln!(diff, "{}", paint(format!("--- {}", name), "31")).ok();
+    writeln!(diff, "{}", paint("+++ actual output".into(), "32")).ok();
+
+    // Hunks are ranges of changes which include the context around them.
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (index, (change, _)) in changes.iter().enumerate() {
+        if *change == Change::Same {
+            continue;
+        }
+        let start = index.saturating_sub(CONTEXT_LINES);
+        let end = (index + CONTEXT_LINES + 1).min(changes.len());
+        match hunks.last_mut() {
+            Some(hunk) if hunk.1 >= start => hunk.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    for (start, end) in hunks {
+        let lines_before = |kind: Change| {
+            changes[..start]
+                .iter()
+                .filter(|(change, _)| *change == Change::Same || *change == kind)
+                .count()
+        };
+        let lines_within = |kind: Change| {
+            changes[start..end]
+                .iter()
+                .filter(|(change, _)| *change == Change::Same || *change == kind)
+                .count()
+        };
+        let (expected_len, actual_len) =
+            (lines_within(Change::Removed), lines_within(Change::Added));
+        writeln!(
+            diff,
+            "{}",
+            paint(
+                format!(
+                    "@@ -{},{} +{},{} @@",
+                    lines_before(Change::Removed) + usize::from(expected_len != 0),
+                    expected_len,
+                    lines_before(Change::Added) + usize::from(actual_len != 0),
+                    actual_len
+                ),
+                "36"
+            )
+        )
+        .ok();
+        for (change, line) in &changes[start..end] {
+            let line = match change {
+                Change::Same => format!(" {}", line),
+                Change::Removed => paint(format!("-{}", line), "31"),
+                Change::Added => paint(format!("+{}", line), "32"),
+            };
+            writeln!(diff, "{}", line).ok();
+        }
+    }
+    diff.pop();
+    diff
+}
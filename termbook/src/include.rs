@@ -0,0 +1,130 @@
+use mdbook::errors::Result;
+use regex::Regex;
+
+use std::{fmt, path::Path};
+
+/// The lines of a file to include, as given after its path like `file.rs:10:25` or `file.rs:name`.
+#[derive(Clone)]
+pub(crate) enum Selection {
+    All,
+    /// The 1-based lines from `start` to `end`, both inclusive. Unset bounds extend to the
+    /// start or end of the file.
+    Lines {
+        start: Option<usize>,
+        end: Option<usize>,
+    },
+    /// The lines between the `ANCHOR: name` and `ANCHOR_END: name` markers.
+    Anchor(String),
+}
+
+impl fmt::Display for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bound = |line: Option<usize>| line.map(|l| l.to_string()).unwrap_or_default();
+        match *self {
+            Selection::All => Ok(()),
+            Selection::Lines { start, end } if start == end => write!(f, "{}", bound(start)),
+            Selection::Lines { start, end } => write!(f, "{}:{}", bound(start), bound(end)),
+            Selection::Anchor(ref name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Split the value of an `include-file` tag into the path of the file and the lines to include.
+pub(crate) fn parse(value: &str) -> std::result::Result<(&str, Selection), String> {
+    let mut path_and_selection = value.splitn(2, ':');
+    let path = path_and_selection.next().unwrap_or_default();
+    let selection = match path_and_selection.next() {
+        None => return Ok((path, Selection::All)),
+        Some(selection) => selection,
+    };
+    let line = |bound: &str| -> std::result::Result<Option<usize>, String> {
+        if bound.is_empty() {
+            return Ok(None);
+        }
+        match bound.parse() {
+            Ok(0) | Err(_) => Err(format!("'{}' is no line number, which start at 1", bound)),
+            Ok(line) => Ok(Some(line)),
+        }
+    };
+    let is_line_range = selection.chars().all(|c| c.is_ascii_digit() || c == ':');
+    let selection = if !is_line_range {
+        Selection::Anchor(selection.to_owned())
+    } else {
+        let mut bounds = selection.splitn(2, ':');
+        let start = line(bounds.next().unwrap_or_default())?;
+        match bounds.next() {
+            None if start.is_none() => return Err("the line range is empty".into()),
+            None => Selection::Lines { start, end: start },
+            Some(end) => {
+                let end = line(end)?;
+                if let (Some(start), Some(end)) = (start, end) {
+                    if start > end {
+                        return Err(format!(
+                            "the line range {}:{} ends before it starts",
+                            start, end
+                        ));
+                    }
+                }
+                Selection::Lines { start, end }
+            }
+        }
+    };
+    Ok((path, selection))
+}
+
+/// Return the lines of `content` chosen by `selection`, where `content` was read from `path`.
+pub(crate) fn select(content: &str, selection: &Selection, path: &Path) -> Result<String> {
+    let lines: Vec<_> = content.lines().collect();
+    let selected = match *selection {
+        Selection::All => return Ok(content.to_owned()),
+        Selection::Lines { start, end } => {
+            let first = start.unwrap_or(1);
+            let last = end.unwrap_or(lines.len());
+            if first > lines.len() || last > lines.len() {
+                return Err(format!(
+                    "Line range {} is out of range for '{}', which has {} line(s).",
+                    selection,
+                    path.display(),
+                    lines.len()
+                ).into());
+            }
+            lines[first - 1..last].to_vec()
+        }
+        Selection::Anchor(ref name) => {
+            let marker = Regex::new(r"\bANCHOR(_END)?:\s*([\w-]+)").expect("valid regex");
+            let is_marker = |line: &str, is_end: bool| {
+                marker
+                    .captures(line)
+                    .is_some_and(|c| c.get(1).is_some() == is_end && &c[2] == name)
+            };
+            let first = lines
+                .iter()
+                .position(|line| is_marker(line, false))
+                .ok_or_else(|| {
+                    format!("Anchor 'ANCHOR: {}' was not found in '{}'.", name, path.display())
+                })?;
+            let len = lines[first + 1..]
+                .iter()
+                .position(|line| is_marker(line, true))
+                .ok_or_else(|| {
+                    format!(
+                        "Anchor '{}' in '{}' is not closed with 'ANCHOR_END: {}'.",
+                        name,
+                        path.display(),
+                        name
+                    )
+                })?;
+            // Markers of other anchors within this one are not part of the content either.
+            lines[first + 1..first + 1 + len]
+                .iter()
+                .filter(|line| !marker.is_match(line))
+                .copied()
+                .collect()
+        }
+    };
+    let mut selected = selected.join("\n");
+    if !selected.is_empty() {
+        selected.push('\n');
+    }
+    Ok(selected)
+}
@@ -1,24 +1,41 @@
 pub use mdbook;
 
 use globset::{Glob, GlobSet, GlobSetBuilder};
-use mdbook::MDBook;
+use mdbook::{Config, MDBook};
 use std::path::Path;
 use mdbook::book::Chapter;
+use toml::Value;
 
 mod rewrite;
+mod cache;
 mod playback;
 mod preprocess;
+mod diagnostics;
+mod config;
+mod exec;
+mod compile;
+mod session;
+mod ansi;
+mod expect;
+mod include;
+mod redact;
+#[cfg(unix)]
+mod pty;
+
+use preprocess::PREPROCESSOR_NAME;
 
 pub use rewrite::*;
 pub use preprocess::*;
 pub use playback::*;
+pub use diagnostics::*;
+pub use cache::clean;
 
 fn exclude_chapter(globs: &GlobSet, chapter: &Chapter) -> bool {
-    if !globs.is_empty() && !globs.is_match(&Path::new(&chapter.name)) {
+    if !globs.is_empty() && !globs.is_match(Path::new(&chapter.name)) {
         let mut is_match = false;
         if let Some(ref section_number) = chapter.number {
             let section_number = format!("{}", section_number);
-            is_match = globs.is_match(&Path::new(&section_number))
+            is_match = globs.is_match(Path::new(&section_number))
         }
         if !is_match {
             return true;
@@ -40,9 +57,41 @@ fn globset_from_strings(globs: &[String]) -> Result<GlobSet, mdbook::errors::Err
 }
 
 /// Open and load an `mdbook` at the given `dir`ectory.
-/// The `RunCodeBlocks` preprocessor will be added to it.
+/// Only chapters matching the given `globs` will have their code blocks executed.
 pub fn load(dir: &Path, globs: Vec<String>) -> mdbook::errors::Result<MDBook> {
-    let mut md = MDBook::load(dir)?;
-    md.with_preprocessor(RunCodeBlocks::new(globs));
+    load_with_preprocessor(dir, RunCodeBlocks::new(globs))
+}
+
+/// Open and load an `mdbook` at the given `dir`ectory.
+/// The given `preprocessor` will be added to it, configured by the
+/// `[preprocessor.run-code-blocks]` table of the `book.toml` file, if present.
+pub fn load_with_preprocessor(
+    dir: &Path,
+    preprocessor: RunCodeBlocks,
+) -> mdbook::errors::Result<MDBook> {
+    let config_location = dir.join("book.toml");
+    let mut config = if config_location.exists() {
+        Config::from_disk(&config_location)?
+    } else {
+        Config::default()
+    };
+    config.update_from_env();
+
+    // Take our table out of the configuration, as `mdbook` would otherwise try to run
+    // an external `mdbook-run-code-blocks` preprocessor.
+    let preprocessor = match config
+        .get_mut("preprocessor")
+        .and_then(Value::as_table_mut)
+        .and_then(|t| t.remove(PREPROCESSOR_NAME))
+    {
+        Some(Value::Table(table)) => preprocessor.with_config(&table)?,
+        Some(_) => {
+            return Err(format!("'preprocessor.{}' must be a table.", PREPROCESSOR_NAME).into())
+        }
+        None => preprocessor,
+    };
+
+    let mut md = MDBook::load_with_config(dir, config)?;
+    md.with_preprocessor(preprocessor);
     Ok(md)
 }
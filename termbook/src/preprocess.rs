@@ -1,4 +1,16 @@
-use crate::{exclude_chapter, globset_from_strings};
+use crate::{
+    ansi,
+    cache::{Cache, KeyBuilder},
+    config::{parse_duration, preset_names, Config, TtySize},
+    exclude_chapter,
+    compile::{self, RustCrate},
+    exec::{self, find_program, Capture, Completion, WorkDir},
+    expect,
+    include::{self, Selection},
+    redact::{self, Preset},
+    session::Session,
+    globset_from_strings, Diagnostic, Diagnostics,
+};
 
 use mdbook::{
     preprocess::{Preprocessor, PreprocessorContext},
@@ -7,29 +19,85 @@ use mdbook::{
     errors::Result,
     errors::Error
 };
-use pulldown_cmark::{Event, Parser};
+use globset::GlobSet;
+use pulldown_cmark::{Event, Parser, Tag};
 use pulldown_cmark_to_cmark::fmt::cmark;
 
 use std::{
-    process::{Child, Command, Stdio},
-    io::{Read, Write},
-    collections::HashMap,
-    path::PathBuf,
-    fs::File
+    process::Command,
+    io::{self, Read},
+    time::Duration,
+    cell::RefCell,
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
+    ops::Range,
+    path::{Path, PathBuf},
+    fs::{self, File},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
 };
 
 /// A preprocessor which runs specifically tagged codeblocks.
 pub struct RunCodeBlocks {
     globs: Vec<String>,
+    config: Config,
+    keep_temp_dirs: bool,
+    update_snapshots: bool,
+    cache: bool,
+    jobs: usize,
 }
 
 impl RunCodeBlocks {
     pub fn new(globs: Vec<String>) -> RunCodeBlocks {
-        RunCodeBlocks { globs }
+        RunCodeBlocks {
+            globs,
+            config: Config::default(),
+            keep_temp_dirs: false,
+            update_snapshots: false,
+            cache: true,
+            jobs: 1,
+        }
+    }
+
+    /// If `keep` is true, temporary directories that programs were executed in are not removed,
+    /// and their paths are printed instead.
+    pub fn keep_temp_dirs(mut self, keep: bool) -> RunCodeBlocks {
+        self.keep_temp_dirs = keep;
+        self
+    }
+
+    /// If `update` is true, snapshot files are written with the output of their block instead
+    /// of being compared with it.
+    pub fn update_snapshots(mut self, update: bool) -> RunCodeBlocks {
+        self.update_snapshots = update;
+        self
+    }
+
+    /// If `enabled` is false, all programs are executed even if the book enables the cache
+    /// and their output is in it.
+    pub fn cache(mut self, enabled: bool) -> RunCodeBlocks {
+        self.cache = enabled;
+        self
+    }
+
+    /// Process up to `jobs` chapters at the same time, or as many as there are CPUs if it is 0.
+    ///
+    /// Chapters sharing a session are still processed one after another, in book order.
+    pub fn jobs(mut self, jobs: usize) -> RunCodeBlocks {
+        self.jobs = jobs;
+        self
+    }
+
+    /// Configure this instance with the `[preprocessor.run-code-blocks]` `table` of a `book.toml` file.
+    pub fn with_config(mut self, table: &toml::value::Table) -> Result<RunCodeBlocks> {
+        self.config = Config::from_table(table)?;
+        Ok(self)
     }
 }
 
-const PREPROCESSOR_NAME: &str = "run-code-blocks";
+pub(crate) const PREPROCESSOR_NAME: &str = "run-code-blocks";
 
 enum Action {
     Exec {
@@ -37,36 +105,243 @@ enum Action {
         desired_exit_status: i32,
     },
     Hide,
+    HideCode,
+    HideOutput,
+    /// Show the output in a collapsed `<details>` element with the given summary, or the
+    /// configured one if `None`.
+    Collapse(Option<String>),
     Prepare(String),
-    IncludeFile(PathBuf),
+    PrepareGlobal(String),
+    IncludeFile(PathBuf, Selection),
     Use(String),
+    Cwd(PathBuf),
+    Env(String, String),
+    ClearEnv,
+    Timeout(Duration),
+    Args(Vec<String>),
+    Session(String),
+    Output(Output),
+    /// Execute in a pseudo-terminal of the given size, or not at all if `None`.
+    Tty(Option<TtySize>),
+    Ansi(bool),
+    ExpectFile(PathBuf),
+    Snapshot(Snapshot),
+    /// Apply only the redaction rules and presets with the given names, or none if `None`.
+    Redact(Option<Vec<String>>),
+    NoCache,
 }
 
+/// The output of an executed program to show in the `output` block.
+#[derive(Clone, Copy, PartialEq)]
+enum Output {
+    Stdout,
+    Stderr,
+    /// All of stdout, followed by all of stderr.
+    Both,
+    /// Stdout and stderr in the order the program wrote them, captured through one pipe.
+    Interleaved,
+    None,
+}
+
+/// The snapshot file to compare the output of a block with.
+#[derive(Clone, PartialEq)]
+enum Snapshot {
+    /// A file named after the number of the block within its chapter.
+    Numbered,
+    /// A file with the given name, which stays the same when blocks are added or removed.
+    Named(String),
+    None,
+}
+
+/// The keys of all tags understood by `Action::from_str`.
+const TAGS: &[&str] = &[
+    "exec", "hide", "hide-code", "hide-output", "collapse", "use", "prepare", "prepare-global",
+    "include-file", "cwd", "env", "clear-env", "no-cache", "timeout", "args", "session", "output",
+    "tty", "ansi", "expect-file", "snapshot", "redact",
+];
+
+/// The keys of tags understood by `mdbook` and `rustdoc`, which are allowed in strict mode.
+const FOREIGN_TAGS: &[&str] = &[
+    "editable", "ignore", "noplayground", "noplaypen", "mdbook-runnable", "hidelines",
+    "should_panic", "no_run", "compile_fail", "test_harness", "edition2015", "edition2018",
+    "edition2021", "edition2024",
+];
+
+/// The summary of collapsed output, unless configured otherwise.
+const COLLAPSE_SUMMARY: &str = "Output";
+
+/// The directory holding snapshot files relative to the book root, unless configured otherwise.
+const SNAPSHOT_DIR: &str = "snapshots";
+
 impl Action {
     fn from_str(program: &str, key: &str, val: Option<&str>) -> Result<Option<Action>> {
         Ok(match key {
-            "hide" => {
+            "hide" | "hide-code" | "hide-output" => {
                 if let Some(v) = val {
                     return Err(format!(
-                        "Encountered value '{}' on 'hide' tag, which is not allowed.",
-                        v
+                        "Encountered value '{}' on '{}' tag, which is not allowed.",
+                        v, key
                     ).into());
                 };
-                Some(Action::Hide)
+                Some(match key {
+                    "hide" => Action::Hide,
+                    "hide-code" => Action::HideCode,
+                    _ => Action::HideOutput,
+                })
             }
+            "collapse" => Some(Action::Collapse(match val {
+                Some("") => {
+                    return Err(
+                        "'collapse' tags need a summary, like 'collapse=Output', or no value."
+                            .into(),
+                    )
+                }
+                summary => summary.map(ToOwned::to_owned),
+            })),
             "use" => Some(Action::Use(val.map(ToOwned::to_owned).ok_or_else(|| {
                 Error::from("'use' tags need a name, like 'use=name'.")
             })?)),
-            "prepare" => Some(Action::Prepare(val.map(ToOwned::to_owned).ok_or_else(
-                || Error::from("'prepare' tags need a name, like 'prepare=name'."),
+            "prepare" | "prepare-global" => {
+                let name = val.ok_or_else(|| {
+                    Error::from(format!("'{}' tags need a name, like '{}=name'.", key, key))
+                })?;
+                if name.contains(CHAPTER_SEPARATOR) {
+                    return Err(format!(
+                        "Invalid name '{}' on '{}' tag, which must not contain '{}'.",
+                        name, key, CHAPTER_SEPARATOR
+                    ).into());
+                }
+                Some(match key {
+                    "prepare" => Action::Prepare(name.to_owned()),
+                    _ => Action::PrepareGlobal(name.to_owned()),
+                })
+            }
+            "include-file" => {
+                let val = val.ok_or_else(|| {
+                    Error::from(
+                        "'include-file' tags need a file name, like 'include-file=../file.md'.",
+                    )
+                })?;
+                let (path, selection) = include::parse(val).map_err(|e| {
+                    format!("Failed to parse lines to include from '{}': {}", val, e)
+                })?;
+                Some(Action::IncludeFile(path.into(), selection))
+            }
+            "cwd" => Some(Action::Cwd(val.map(PathBuf::from).ok_or_else(|| {
+                Error::from("'cwd' tags need a directory, like 'cwd=../directory'.")
+            })?)),
+            "env" => {
+                let assignment = val.ok_or_else(|| {
+                    Error::from("'env' tags need a variable assignment, like 'env=NAME=value'.")
+                })?;
+                let mut nvi = assignment.splitn(2, '=');
+                match (nvi.next().map(str::trim), nvi.next()) {
+                    (Some(name), Some(value)) if !name.is_empty() => {
+                        Some(Action::Env(name.to_owned(), value.to_owned()))
+                    }
+                    _ => {
+                        return Err(format!(
+                            "Could not parse variable assignment '{}' of 'env' tag, which should look like 'env=NAME=value'.",
+                            assignment
+                        ).into())
+                    }
+                }
+            }
+            "no-cache" => {
+                if let Some(v) = val {
+                    return Err(format!(
+                        "Encountered value '{}' on 'no-cache' tag, which is not allowed.",
+                        v
+                    ).into());
+                };
+                Some(Action::NoCache)
+            }
+            "clear-env" => {
+                if let Some(v) = val {
+                    return Err(format!(
+                        "Encountered value '{}' on 'clear-env' tag, which is not allowed.",
+                        v
+                    ).into());
+                };
+                Some(Action::ClearEnv)
+            }
+            "timeout" => Some(Action::Timeout(match val {
+                Some(val) => parse_duration(val).map_err(|e| {
+                    format!(
+                        "Failed to parse duration from '{}' for 'timeout' key with error: {}",
+                        val, e
+                    )
+                })?,
+                None => return Err("'timeout' tags need a duration, like 'timeout=30s'.".into()),
+            })),
+            "args" => Some(Action::Args(
+                val.map(|v| v.split_whitespace().map(ToOwned::to_owned).collect())
+                    .ok_or_else(|| Error::from("'args' tags need arguments, like 'args=-u -'."))?,
+            )),
+            "session" => Some(Action::Session(val.map(ToOwned::to_owned).ok_or_else(
+                || Error::from("'session' tags need a name, like 'session=name'."),
             )?)),
-            "include-file" => Some(Action::IncludeFile(val.map(PathBuf::from).ok_or_else(
+            "output" => Some(Action::Output(match val {
+                Some("stdout") => Output::Stdout,
+                Some("stderr") => Output::Stderr,
+                Some("both") => Output::Both,
+                Some("interleaved") => Output::Interleaved,
+                Some("none") => Output::None,
+                Some(v) => {
+                    return Err(format!(
+                        "Unknown value '{}' on 'output' tag, expected one of 'stdout', 'stderr', 'both', 'interleaved' or 'none'.",
+                        v
+                    ).into())
+                }
+                None => return Err("'output' tags need a value, like 'output=stdout'.".into()),
+            })),
+            "tty" => Some(Action::Tty(match val {
+                None => Some(TtySize::default()),
+                Some("off") => None,
+                Some(size) => Some(TtySize::parse(size).map_err(|e| {
+                    format!("Failed to parse size of 'tty' tag: {}", e)
+                })?),
+            })),
+            "ansi" => Some(Action::Ansi(match val {
+                None => true,
+                Some("off") => false,
+                Some(v) => {
+                    return Err(format!(
+                        "Encountered value '{}' on 'ansi' tag, expected none or 'off'.",
+                        v
+                    ).into())
+                }
+            })),
+            "expect-file" => Some(Action::ExpectFile(val.map(PathBuf::from).ok_or_else(
                 || {
                     Error::from(
-                        "'include-file' tags need a file name, like 'include-file=../file.md'.",
+                        "'expect-file' tags need a file name, like 'expect-file=output.txt'.",
                     )
                 },
             )?)),
+            "snapshot" => Some(Action::Snapshot(match val {
+                None => Snapshot::Numbered,
+                Some("off") => Snapshot::None,
+                Some(name) if name.is_empty() || name.contains(['/', '\\']) => {
+                    return Err(format!(
+                        "Invalid name '{}' on 'snapshot' tag, which must be a file name without directories.",
+                        name
+                    ).into())
+                }
+                Some(name) => Snapshot::Named(name.to_owned()),
+            })),
+            "redact" => Some(Action::Redact(match val {
+                Some("off") => None,
+                Some(names) if !names.trim().is_empty() => {
+                    Some(names.split_whitespace().map(ToOwned::to_owned).collect())
+                }
+                _ => {
+                    return Err(
+                        "'redact' tags need the names of rules or presets, like 'redact=home', or 'off'."
+                            .into(),
+                    )
+                }
+            })),
             "exec" => Some(Action::Exec {
                 program: program.to_owned(),
                 desired_exit_status: match val {
@@ -84,13 +359,226 @@ impl Action {
     }
 }
 
+/// How escape sequences in the output of programs are presented by the renderer the book
+/// is preprocessed for.
+#[derive(Clone, Copy, Default)]
+enum AnsiRendering {
+    /// As they are, for renderers emulating a terminal.
+    #[default]
+    Keep,
+    /// Not at all, for renderers producing plain text.
+    Strip,
+    /// As styled HTML.
+    Html,
+}
+
+impl AnsiRendering {
+    fn for_renderer(renderer: &str) -> AnsiRendering {
+        match renderer {
+            "html" => AnsiRendering::Html,
+            "playback" => AnsiRendering::Keep,
+            _ => AnsiRendering::Strip,
+        }
+    }
+}
+
+/// The info string of code blocks with the expected stdout of the executed block before them.
+const EXPECT_BLOCK: &str = "expect-output";
+
+fn is_expect_block(info: &str) -> bool {
+    info.trim() == EXPECT_BLOCK
+}
+
+/// An `expect-output` block following a code block.
+#[derive(Default)]
+struct Expectation {
+    /// If true, the `expect-output` block follows a code block with an `exec` tag.
+    follows_exec: bool,
+    /// The stdout of the preceding block, if it was executed successfully.
+    actual: Option<String>,
+    /// The content of the `expect-output` block.
+    expected: String,
+    /// If true, the `expect-output` block is hidden along with the block before it.
+    hide: bool,
+    /// The byte range of the `expect-output` block's info string within the chapter.
+    span: Range<usize>,
+    /// The summary of the `<details>` element to show the `expect-output` block in, if it is collapsed.
+    summary: Option<String>,
+}
+
+/// Separates the chapter from the name in references to `prepare` blocks of other chapters,
+/// like `use=chapter::name`.
+const CHAPTER_SEPARATOR: &str = "::";
+
+/// A `prepare` block, whose used blocks are resolved only when it is used itself.
+#[derive(Clone)]
+struct PreparedBlock {
+    /// The code of the block, without the code of the blocks it uses.
+    code: String,
+    /// The files of the block's `include-file` tags, which are read whenever it is used.
+    includes: Vec<(PathBuf, Selection)>,
+    /// The references of the block's `use` tags, in the order they were given.
+    uses: Vec<String>,
+    /// The chapter defining the block, in which its references are resolved.
+    chapter: String,
+    /// The number of the block within its chapter.
+    block: usize,
+}
+
+/// The `prepare` blocks of the whole book by name.
+#[derive(Clone, Default)]
+struct Prepared {
+    /// Blocks with the `prepare-global` tag, which are visible to all chapters.
+    global: HashMap<String, PreparedBlock>,
+    /// Blocks with the `prepare` tag by the chapter they are visible in.
+    chapters: HashMap<String, HashMap<String, PreparedBlock>>,
+}
+
+impl Prepared {
+    /// The block that `id` refers to when used in `chapter`, which is either a name defined
+    /// in that chapter or globally, or qualified with the chapter defining it.
+    ///
+    /// The block is returned along with its qualified name, which is unique within the book.
+    fn find(&self, chapter: &str, id: &str) -> std::result::Result<(String, &PreparedBlock), String> {
+        let local = |chapter: &str, name: &str| {
+            self.chapters
+                .get(chapter)
+                .and_then(|names| names.get(name))
+                .map(|block| (format!("{}{}{}", chapter, CHAPTER_SEPARATOR, name), block))
+        };
+        if let Some((chapter, name)) = id.rsplit_once(CHAPTER_SEPARATOR) {
+            return local(chapter, name).ok_or_else(|| {
+                format!(
+                    "Reference named '{}' was not added with a 'prepare' block in chapter '{}'.",
+                    name, chapter
+                )
+            });
+        }
+        if let Some(found) = local(chapter, id)
+            .or_else(|| self.global.get(id).map(|block| (id.to_owned(), block)))
+        {
+            return Ok(found);
+        }
+        let mut defining_chapters: Vec<_> = self
+            .chapters
+            .iter()
+            .filter(|(_, names)| names.contains_key(id))
+            .map(|(chapter, _)| chapter)
+            .collect();
+        defining_chapters.sort();
+        Err(match defining_chapters.first() {
+            Some(other) => format!(
+                "Reference named '{id}' was not added with a 'prepare' block in chapter \
+                 '{chapter}', only in chapter '{other}'. Use it with 'use={other}{sep}{id}', \
+                 or add it with 'prepare-global={id}' to use it everywhere.",
+                id = id,
+                chapter = chapter,
+                other = other,
+                sep = CHAPTER_SEPARATOR
+            ),
+            None => format!(
+                "Reference named '{}' was not added with a 'prepare' block.",
+                id
+            ),
+        })
+    }
+
+    /// Append the block that `id` refers to in `chapter` to `blocks` along with its qualified
+    /// name, preceded by the blocks it uses, recursively.
+    ///
+    /// `chain` holds the qualified names of the blocks through which `id` is used, and blocks
+    /// in `included` are skipped as they are already part of `blocks`.
+    fn include<'a>(
+        &'a self,
+        chapter: &str,
+        id: &str,
+        chain: &mut Vec<String>,
+        included: &mut HashSet<String>,
+        blocks: &mut Vec<(String, &'a PreparedBlock)>,
+    ) -> std::result::Result<(), String> {
+        let (name, block) = self.find(chapter, id).map_err(|cause| match chain.is_empty() {
+            true => cause,
+            false => format!("{} It is used through {}.", cause, format_chain(chain)),
+        })?;
+        if chain.contains(&name) {
+            chain.push(name);
+            return Err(format!(
+                "The 'use' tags of 'prepare' blocks form a cycle: {}.",
+                format_chain(chain)
+            ));
+        }
+        if included.contains(&name) {
+            return Ok(());
+        }
+        chain.push(name);
+        for used in &block.uses {
+            self.include(&block.chapter, used, chain, included, blocks)?;
+        }
+        let name = chain.pop().expect("pushed above");
+        included.insert(name.clone());
+        blocks.push((name, block));
+        Ok(())
+    }
+}
+
+/// Format the qualified names of `prepare` blocks using each other, like `'a' -> 'b'`.
+fn format_chain(chain: &[String]) -> String {
+    chain
+        .iter()
+        .map(|name| format!("'{}'", name))
+        .collect::<Vec<_>>()
+        .join(" -> ")
+}
+
+/// An action along with the byte range of its tag within the chapter.
+type TaggedAction = (Action, Range<usize>);
+
+/// An error caused by a code block.
+struct BlockError {
+    block: usize,
+    /// The byte range of the offending tag or code block fence within the chapter.
+    span: Range<usize>,
+    cause: Error,
+}
+
 #[derive(Default)]
 struct State {
-    actions: Vec<Action>,
+    actions: Vec<TaggedAction>,
     code: String,
-    error: Option<Error>,
-    prepare: HashMap<String, String>,
+    block: usize,
+    /// The number of the current block among the blocks with an `exec` tag in the chapter,
+    /// which names its snapshot file.
+    exec_block: usize,
+    errors: Vec<BlockError>,
+    prepare: Prepared,
     book_root: PathBuf,
+    config: Config,
+    rust_crate: RefCell<Option<RustCrate>>,
+    /// The part of cache keys identifying the Rust toolchain and path dependencies,
+    /// computed on first use.
+    compiler_key: RefCell<Option<String>>,
+    /// Running sessions by name, along with the temporary directory they were started in.
+    sessions: RefCell<HashMap<String, (Session, Option<WorkDir>)>>,
+    /// If true, programs of the current chapter are executed in a temporary directory.
+    isolated: bool,
+    keep_temp_dirs: bool,
+    /// The temporary directory of the current chapter, created on first use.
+    work_dir: RefCell<Option<WorkDir>>,
+    ansi_rendering: AnsiRendering,
+    /// If true, the renderer shows `<details>` elements, which collapsed output is put in.
+    collapsible: bool,
+    expectation: Option<Expectation>,
+    /// If true, the events of an `expect-output` block are being processed.
+    in_expect_block: bool,
+    update_snapshots: bool,
+    /// The path of the current chapter's file, relative to the source directory.
+    chapter_path: PathBuf,
+    /// The cache for the output of programs, if it is used.
+    cache: Option<Cache>,
+    /// The cache key of the block executed last in the current chapter.
+    previous_key: RefCell<Option<String>>,
+    /// If true, chapters are only scanned for `prepare` blocks, which are added to `prepare`.
+    resolving: bool,
 }
 
 impl State {
@@ -98,152 +586,1141 @@ impl State {
         !self.actions.is_empty()
     }
 
-    fn should_hide(&self) -> bool {
+    /// Return an error for each tag of the code block with the `info` string at byte `offset`
+    /// which is unknown, if these are rejected.
+    fn check_unknown_tags(&self, info: &str, offset: usize) -> Vec<(Range<usize>, Error)> {
+        if !self.config.strict_tags {
+            return Vec::new();
+        }
+        let (program, tags) = split_info(info, offset);
+        tags.into_iter()
+            .filter(|tag| {
+                // Tags with invalid values are known, and reported when parsing them.
+                matches!(Action::from_str(program, tag.key, tag.value), Ok(None))
+                    && !FOREIGN_TAGS.contains(&tag.key)
+                    && !self.config.allowed_tags.iter().any(|allowed| allowed == tag.key)
+            })
+            .map(|tag| (tag.span, unknown_tag(tag.key)))
+            .collect()
+    }
+
+    fn hides_code(&self) -> bool {
+        self.has_action(|a| matches!(*a, Action::Hide | Action::HideCode))
+    }
+
+    fn hides_output(&self) -> bool {
+        self.has_action(|a| matches!(*a, Action::Hide | Action::HideOutput))
+    }
+
+    /// The summary of the `<details>` element to show the output of the current block in,
+    /// if it is collapsed and the renderer supports it.
+    fn collapse_summary(&self) -> Option<String> {
+        if !self.collapsible {
+            return None;
+        }
+        self.actions.iter().rev().find_map(|(a, _)| match *a {
+            Action::Collapse(ref summary) => Some(
+                summary
+                    .as_ref()
+                    .or(self.config.collapse_summary.as_ref())
+                    .map_or(COLLAPSE_SUMMARY, String::as_str)
+                    .to_owned(),
+            ),
+            _ => None,
+        })
+    }
+
+    fn has_action(&self, predicate: impl Fn(&Action) -> bool) -> bool {
+        self.actions.iter().any(|(a, _)| predicate(a))
+    }
+
+    /// The name of the current chapter in references to its `prepare` blocks, which is the
+    /// path of its file without extension.
+    fn chapter_id(&self) -> String {
+        self.chapter_path
+            .with_extension("")
+            .to_string_lossy()
+            .replace('\\', "/")
+    }
+
+    /// Prepend the code of the blocks used by the current block to its code, in the order
+    /// of its `use` tags.
+    ///
+    /// Returns false if any of them could not be used.
+    fn prepend_used_code(&mut self) -> bool {
+        let chapter = self.chapter_id();
+        let own_names: Vec<_> = self
+            .actions
+            .iter()
+            .filter_map(|(action, _)| match *action {
+                Action::Prepare(ref id) => {
+                    Some(format!("{}{}{}", chapter, CHAPTER_SEPARATOR, id))
+                }
+                Action::PrepareGlobal(ref id) => Some(id.to_owned()),
+                _ => None,
+            })
+            .collect();
+        let prefix = self.config.hidden_line_prefix.as_deref();
+        let mut included = HashSet::new();
+        let mut blocks = Vec::new();
+        let mut code = String::new();
+        let mut resolved = true;
+        for (action, tag) in &self.actions {
+            if let Action::Use(ref id) = *action {
+                let mut chain = own_names.clone();
+                let start = blocks.len();
+                let result = self
+                    .prepare
+                    .include(&chapter, id, &mut chain, &mut included, &mut blocks)
+                    .map_err(Error::from)
+                    .and_then(|()| {
+                        // Included files are read now, as they may be written by blocks executed before.
+                        blocks[start..].iter().try_for_each(|(name, block)| {
+                            code.push_str(&block.code);
+                            for (path, selection) in &block.includes {
+                                let buf = read_include(&self.book_root, path, selection)
+                                    .map_err(|e| {
+                                        e.chain_err(|| {
+                                            format!("Could not use the 'prepare' block '{}'", name)
+                                        })
+                                    })?;
+                                add_code(&buf, prefix, &mut code);
+                            }
+                            Ok(())
+                        })
+                    });
+                if let Err(cause) = result {
+                    resolved = false;
+                    self.errors.push(BlockError {
+                        block: self.block,
+                        span: tag.clone(),
+                        cause,
+                    });
+                }
+            }
+        }
+        self.code.insert_str(0, &code);
+        resolved
+    }
+
+    /// Add the current block under the names of its `prepare` tags, unless blocks with
+    /// these names were added before.
+    fn add_prepared_block(&mut self) {
+        let block = PreparedBlock {
+            code: self.code.clone(),
+            includes: self
+                .actions
+                .iter()
+                .filter_map(|(action, _)| match *action {
+                    Action::IncludeFile(ref path, ref selection) => {
+                        Some((path.clone(), selection.clone()))
+                    }
+                    _ => None,
+                })
+                .collect(),
+            uses: self
+                .actions
+                .iter()
+                .filter_map(|(action, _)| match *action {
+                    Action::Use(ref id) => Some(id.to_owned()),
+                    _ => None,
+                })
+                .collect(),
+            chapter: self.chapter_id(),
+            block: self.block,
+        };
+        for (action, _) in &self.actions {
+            match *action {
+                Action::Prepare(ref id) => {
+                    self.prepare
+                        .chapters
+                        .entry(block.chapter.clone())
+                        .or_default()
+                        .entry(id.to_owned())
+                        .or_insert_with(|| block.clone());
+                }
+                Action::PrepareGlobal(ref id) => {
+                    self.prepare
+                        .global
+                        .entry(id.to_owned())
+                        .or_insert_with(|| block.clone());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// The directory to execute programs in, if it is neither the current working directory
+    /// nor a temporary directory.
+    fn cwd(&self) -> Option<PathBuf> {
         self.actions
             .iter()
-            .any(|a| if let Action::Hide = *a { true } else { false })
+            .rev()
+            .find_map(|(a, _)| match *a {
+                Action::Cwd(ref path) => Some(path),
+                _ => None,
+            })
+            .or(if self.isolated {
+                None
+            } else {
+                self.config.cwd.as_ref()
+            })
+            .map(|path| self.book_root.join(path))
     }
 
     fn apply_end_of_codeblock_actions(&mut self, events: &mut Vec<Event>, dry_run: bool) {
-        for action in &self.actions {
+        // Blocks using others which can't be used would fail in confusing ways, if at all.
+        let uses_resolved = self.resolving || self.prepend_used_code();
+        for (action, tag) in &self.actions {
             match *action {
-                Action::IncludeFile(ref path) => {
-                    let mut buf = String::new();
-                    let file_path = self.book_root.join(path);
-                    match File::open(&file_path) {
-                        Ok(mut f) => match f.read_to_string(&mut buf) {
-                            Ok(_) => {
-                                if !buf.ends_with('\n') {
-                                    buf.push('\n')
-                                }
-                                self.code.push_str(&buf);
-                                let pos = events.len() - 1;
-                                events.insert(pos, Event::Text(buf.into()));
-                            }
-                            Err(e) => {
-                                self.error = Some(Error::from(e).chain_err(|| {
-                                    format!("Could not read file at '{}'", file_path.display())
-                                }))
-                            }
-                        },
-                        Err(e) => {
-                            self.error = Some(Error::from(e).chain_err(|| {
-                                format!(
-                                "include-file={} failed as the file at '{}' could not be opened",
-                                path.display(), file_path.display())
-                            }))
+                // Files are read when `prepare` blocks are used, which is after the resolution.
+                Action::IncludeFile(..) if self.resolving => {}
+                Action::IncludeFile(ref path, ref selection) => {
+                    match read_include(&self.book_root, path, selection) {
+                        Ok(buf) => {
+                            let prefix = self.config.hidden_line_prefix.as_deref();
+                            let shown = add_code(&buf, prefix, &mut self.code);
+                            let pos = events.len() - 1;
+                            events.insert(pos, Event::Text(shown.into()));
                         }
+                        Err(cause) => self.errors.push(BlockError {
+                            block: self.block,
+                            span: tag.clone(),
+                            cause,
+                        }),
                     }
                 }
-                Action::Hide => {}
-                Action::Use(ref id) => match self.prepare.get(id) {
-                    Some(code) => self.code.insert_str(0, code),
-                    None => {
-                        self.error = Some(
-                            format!(
-                                "Reference named '{}' was not yet added with a 'prepare' block.",
-                                id
-                            ).into(),
-                        )
+                Action::Hide | Action::HideCode | Action::HideOutput | Action::Collapse(_)
+                | Action::Cwd(_) | Action::Env(..) | Action::ClearEnv
+                | Action::Timeout(_) | Action::Args(_) | Action::Session(_)
+                | Action::Output(_) | Action::Tty(_) | Action::Ansi(_)
+                | Action::ExpectFile(_) | Action::Snapshot(_) | Action::Redact(_)
+                | Action::NoCache => {}
+                Action::Use(_) => {}
+                Action::Prepare(ref id) if !self.resolving => {
+                    let chapter = self.chapter_id();
+                    let first = self
+                        .prepare
+                        .chapters
+                        .get(&chapter)
+                        .and_then(|names| names.get(id));
+                    let cause = match self.prepare.global.get(id) {
+                        _ if first.is_some_and(|first| first.block != self.block) => Some(format!(
+                            "A 'prepare' block named '{}' was already added in this chapter.",
+                            id
+                        )),
+                        Some(global) if global.chapter != chapter || global.block != self.block => {
+                            Some(format!(
+                                "A 'prepare-global' block named '{}' was already added in chapter '{}', \
+                                 which this block would hide.",
+                                id, global.chapter
+                            ))
+                        }
+                        _ => None,
+                    };
+                    if let Some(cause) = cause {
+                        self.errors.push(BlockError {
+                            block: self.block,
+                            span: tag.clone(),
+                            cause: cause.into(),
+                        })
                     }
-                },
-                Action::Prepare(ref id) => {
-                    self.prepare.insert(id.to_owned(), self.code.clone());
                 }
+                Action::PrepareGlobal(ref id) if !self.resolving => {
+                    let chapter = self.chapter_id();
+                    let cause = match self.prepare.global.get(id) {
+                        Some(first) if first.chapter != chapter || first.block != self.block => {
+                            Some(format!(
+                                "A 'prepare-global' block named '{}' was already added in chapter '{}'.",
+                                id, first.chapter
+                            ))
+                        }
+                        _ => {
+                            let mut hiding: Vec<_> = self
+                                .prepare
+                                .chapters
+                                .iter()
+                                .filter(|(other, names)| {
+                                    names.get(id).is_some_and(|local| {
+                                        **other != chapter || local.block != self.block
+                                    })
+                                })
+                                .map(|(other, _)| other)
+                                .collect();
+                            hiding.sort();
+                            hiding.first().map(|other| {
+                                format!(
+                                    "A 'prepare' block named '{}' was already added in chapter '{}', \
+                                     which would hide this block there.",
+                                    id, other
+                                )
+                            })
+                        }
+                    };
+                    if let Some(cause) = cause {
+                        self.errors.push(BlockError {
+                            block: self.block,
+                            span: tag.clone(),
+                            cause: cause.into(),
+                        })
+                    }
+                }
+                Action::Prepare(_) | Action::PrepareGlobal(_) => {}
                 Action::Exec {
                     ref program,
                     desired_exit_status,
                 } => {
-                    if dry_run {
-                        return;
+                    if dry_run || !uses_resolved {
+                        continue;
                     }
-                    let spawn_result = Command::new(program)
-                        .stdin(Stdio::piped())
-                        .stdout(Stdio::piped())
-                        .stderr(Stdio::piped())
-                        .spawn()
-                        .map_err(|e| {
-                            format!("Failed to execute '{}' with error: {}", program, e).into()
-                        })
-                        .and_then(|mut c: Child| {
-                            c.stdin
-                                .as_mut()
-                                .expect("stdin to be configured")
-                                .write_all(self.code.as_bytes())
-                                .and_then(|_| c.wait_with_output())
-                                .map_err(Into::into)
-                        });
-                    match spawn_result {
-                        Ok(mut output) => {
-                            eprintln!(
-                                "{}: Executed program '{}' with '{:?}'.",
-                                PREPROCESSOR_NAME, program, self.code
-                            );
-                            let actual_exit_status = output.status.code().unwrap_or(1);
-                            if actual_exit_status != desired_exit_status {
-                                self.error = Some(
-                                    format!(
-                                        "After running '{}': Expected exit status '{}' to be '{}'\nstdout: {}\nstderr: {}",
-                                        program, actual_exit_status, desired_exit_status,
-                                        String::from_utf8_lossy(&output.stdout),
-                                        String::from_utf8_lossy(&output.stderr),
-                                    ).into(),
-                                );
-                            } else {
-                                use pulldown_cmark::Event::*;
-                                use pulldown_cmark::Tag::*;
-                                events.push(Start(CodeBlock("output".into())));
-                                events.push(Text({
-                                    if let Some(c) = output.stdout.last().cloned() {
-                                        if c != b'\n' {
-                                            output.stdout.push(b'\n');
-                                        }
-                                    }
-                                    String::from_utf8_lossy(&output.stdout).into_owned().into()
-                                }));
-                                events.push(Text({
-                                    if let Some(c) = output.stderr.last().cloned() {
-                                        if c != b'\n' {
-                                            output.stderr.push(b'\n');
-                                        }
-                                    }
-                                    String::from_utf8_lossy(&output.stderr).into_owned().into()
-                                }));
-                                events.push(End(CodeBlock("output".into())));
+                    match self.execute(program, desired_exit_status) {
+                        Ok(output) => {
+                            let output = self.redact(output);
+                            let stdout = newline_terminated(&output.stdout);
+                            for (span, cause) in self.check_expect_files(&stdout) {
+                                self.errors.push(BlockError {
+                                    block: self.block,
+                                    span,
+                                    cause,
+                                });
+                            }
+                            if let Some((path, span)) = self.snapshot(tag) {
+                                let shown = self.shown_streams(&output).concat();
+                                if let Err(cause) = self.check_snapshot(&path, &shown) {
+                                    self.errors.push(BlockError {
+                                        block: self.block,
+                                        span,
+                                        cause,
+                                    });
+                                }
+                            }
+                            match self.expectation {
+                                Some(ref mut expectation) => expectation.actual = Some(stdout),
+                                None => events.extend(self.output_events(output)),
                             }
                         }
-                        Err(e) => self.error = Some(e),
+                        Err(e) => self.errors.push(BlockError {
+                            block: self.block,
+                            span: tag.clone(),
+                            cause: e,
+                        }),
                     }
                 }
             }
         }
+        if self.resolving {
+            self.add_prepared_block();
+        }
+    }
+
+    /// Replace text in the `output` of the current block with the redaction rules and presets
+    /// selected for it.
+    fn redact(&self, output: Completion) -> Completion {
+        let selected = self.actions.iter().rev().find_map(|(a, _)| match *a {
+            Action::Redact(ref names) => Some(names),
+            _ => None,
+        });
+        let (presets, rules): (Vec<Preset>, Vec<_>) = match selected {
+            Some(None) => return output,
+            Some(Some(names)) => (
+                Preset::ALL
+                    .iter()
+                    .copied()
+                    .filter(|preset| names.iter().any(|name| name == preset.name()))
+                    .collect(),
+                self.config
+                    .redact
+                    .iter()
+                    .filter(|rule| rule.name.as_ref().is_some_and(|n| names.contains(n)))
+                    .collect(),
+            ),
+            None => (
+                self.config.redact_presets.clone(),
+                self.config.redact.iter().collect(),
+            ),
+        };
+        if presets.is_empty() && rules.is_empty() {
+            return output;
+        }
+        let paths: Vec<_> = presets
+            .into_iter()
+            .filter_map(|preset| {
+                let path = match preset {
+                    Preset::TempDir => self.temp_dir(),
+                    Preset::BookRoot => self.book_root.canonicalize().ok(),
+                    Preset::Home => std::env::var_os("HOME").map(PathBuf::from),
+                };
+                path.map(|path| (path, preset.replacement()))
+            })
+            .collect();
+        Completion {
+            status: output.status,
+            stdout: redact::apply(&output.stdout, &paths, &rules),
+            stderr: redact::apply(&output.stderr, &paths, &rules),
+        }
+    }
+
+    /// The tag and cause of the first name on the `redact` tags of the current block which
+    /// is neither the name of a redaction rule nor of a preset.
+    fn check_redaction_names(&self) -> Option<(Range<usize>, Error)> {
+        self.actions.iter().find_map(|(a, tag)| {
+            let names = match *a {
+                Action::Redact(Some(ref names)) => names,
+                _ => return None,
+            };
+            let unknown = names.iter().find(|name| {
+                Preset::from_name(name).is_none()
+                    && !self.config.redact.iter().any(|rule| rule.name.as_ref() == Some(*name))
+            })?;
+            Some((
+                tag.clone(),
+                format!(
+                    "Unknown name '{}' on 'redact' tag, expected 'off', the name of a rule in 'redact' or one of {}.",
+                    unknown,
+                    preset_names()
+                ).into(),
+            ))
+        })
+    }
+
+    /// The temporary directory the current block was executed in, if any.
+    fn temp_dir(&self) -> Option<PathBuf> {
+        match self.session() {
+            Some(name) => self
+                .sessions
+                .borrow()
+                .get(name)
+                .and_then(|(_, work_dir)| work_dir.as_ref().map(|d| d.path().to_owned())),
+            None => self.work_dir.borrow().as_ref().map(|d| d.path().to_owned()),
+        }
+    }
+
+    /// The streams of `output` to show for the current block.
+    fn shown_streams(&self, output: &Completion) -> Vec<String> {
+        let streams = match self.output() {
+            Output::None => return Vec::new(),
+            Output::Stdout | Output::Interleaved => vec![&output.stdout],
+            Output::Stderr => vec![&output.stderr],
+            Output::Both => vec![&output.stdout, &output.stderr],
+        };
+        streams.into_iter().map(|stream| newline_terminated(stream)).collect()
+    }
+
+    /// The events of the `output` block showing the `output` of the current block.
+    fn output_events(&self, output: Completion) -> Vec<Event<'static>> {
+        use pulldown_cmark::Event::*;
+        use pulldown_cmark::Tag::*;
+        let streams = self.shown_streams(&output);
+        if streams.is_empty() {
+            return Vec::new();
+        }
+        let streams = streams.into_iter();
+        let rendering = if self.ansi() {
+            self.ansi_rendering
+        } else {
+            AnsiRendering::Keep
+        };
+        if let AnsiRendering::Html = rendering {
+            return vec![Html(format!(
+                "<pre><code class=\"language-output\">{}</code></pre>\n",
+                ansi::to_html(&streams.collect::<String>())
+            ).into())];
+        }
+        let mut events = vec![Start(CodeBlock("output".into()))];
+        for stream in streams {
+            events.push(Text(match rendering {
+                AnsiRendering::Strip => ansi::strip(&stream).into(),
+                _ => stream.into(),
+            }));
+        }
+        events.push(End(CodeBlock("output".into())));
+        events
+    }
+
+    /// Compare the `stdout` of the current block with the content of its `expect-file` tags,
+    /// and return the tag and cause of each mismatch.
+    fn check_expect_files(&self, stdout: &str) -> Vec<(Range<usize>, Error)> {
+        let mut mismatches = Vec::new();
+        for (action, tag) in &self.actions {
+            if let Action::ExpectFile(ref path) = *action {
+                let file_path = self.book_root.join(path);
+                let expected = match std::fs::read_to_string(&file_path) {
+                    Ok(expected) => expected,
+                    Err(e) => {
+                        mismatches.push((
+                            tag.clone(),
+                            Error::from(e).chain_err(|| {
+                                format!("Could not read expected output at '{}'", file_path.display())
+                            }),
+                        ));
+                        continue;
+                    }
+                };
+                if let Some(diff) = expect::mismatch(
+                    &path.display().to_string(),
+                    &newline_terminated(expected.as_bytes()),
+                    stdout,
+                ) {
+                    mismatches.push((
+                        tag.clone(),
+                        format!(
+                            "Output did not match the expected output in '{}':\n{}",
+                            path.display(),
+                            diff
+                        ).into(),
+                    ))
+                }
+            }
+        }
+        mismatches
+    }
+
+    /// The path of the snapshot file of the current block relative to the book root, if it has
+    /// one, along with the tag causing it, which is the `exec` tag at `exec_tag` unless there
+    /// is a `snapshot` tag.
+    fn snapshot(&self, exec_tag: &Range<usize>) -> Option<(PathBuf, Range<usize>)> {
+        let (name, span) = match self.actions.iter().rev().find_map(|(a, tag)| match *a {
+            Action::Snapshot(ref snapshot) => Some((snapshot, tag)),
+            _ => None,
+        }) {
+            Some((Snapshot::None, _)) => return None,
+            Some((Snapshot::Named(name), tag)) => (name.to_owned(), tag),
+            Some((Snapshot::Numbered, tag)) => (self.exec_block.to_string(), tag),
+            None if self.config.snapshots => (self.exec_block.to_string(), exec_tag),
+            None => return None,
+        };
+        let path = self
+            .config
+            .snapshot_dir
+            .as_deref()
+            .unwrap_or_else(|| Path::new(SNAPSHOT_DIR))
+            .join(self.chapter_path.with_extension(""))
+            .join(format!("{}.out", name));
+        Some((path, span.clone()))
+    }
+
+    /// Compare the `shown` output of the current block with its snapshot file at `path`,
+    /// or write it there if snapshots are updated and it doesn't match.
+    fn check_snapshot(&self, path: &Path, shown: &str) -> Result<()> {
+        let file_path = self.book_root.join(path);
+        let recorded = match fs::read_to_string(&file_path) {
+            Ok(recorded) => Some(recorded),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => {
+                return Err(Error::from(e).chain_err(|| {
+                    format!("Could not read snapshot at '{}'", file_path.display())
+                }))
+            }
+        };
+        let diff = match recorded {
+            Some(ref recorded) => {
+                match expect::mismatch(&path.display().to_string(), recorded, shown) {
+                    None => return Ok(()),
+                    diff => diff,
+                }
+            }
+            None => None,
+        };
+        if self.update_snapshots {
+            if let Some(dir) = file_path.parent() {
+                fs::create_dir_all(dir).map_err(|e| {
+                    Error::from(e).chain_err(|| {
+                        format!("Could not create snapshot directory at '{}'", dir.display())
+                    })
+                })?;
+            }
+            fs::write(&file_path, shown).map_err(|e| {
+                Error::from(e).chain_err(|| {
+                    format!("Could not write snapshot at '{}'", file_path.display())
+                })
+            })?;
+            eprintln!(
+                "{}: Updated snapshot at '{}'.",
+                PREPROCESSOR_NAME,
+                path.display()
+            );
+            return Ok(());
+        }
+        Err(match diff {
+            Some(diff) => format!(
+                "Output did not match the snapshot in '{}', run 'termbook build --update-snapshots' to accept it:\n{}",
+                path.display(),
+                diff
+            ),
+            None => format!(
+                "Snapshot '{}' does not exist yet, run 'termbook build --update-snapshots' to record it.",
+                path.display()
+            ),
+        }.into())
+    }
+
+    /// Compare the output of the block before the `expect-output` block that just ended with
+    /// its content.
+    fn check_expectation(&mut self) {
+        let expectation = match self.expectation.take() {
+            Some(expectation) => expectation,
+            None => return,
+        };
+        if !expectation.follows_exec {
+            self.errors.push(BlockError {
+                block: self.block,
+                span: expectation.span,
+                cause: format!("'{}' block has no preceding executed block.", EXPECT_BLOCK).into(),
+            });
+            return;
+        }
+        let actual = match expectation.actual {
+            Some(ref actual) => actual,
+            None => return,
+        };
+        let cause = match expect::mismatch(
+            &format!("'{}' block", EXPECT_BLOCK),
+            &expectation.expected,
+            actual,
+        ) {
+            None => return,
+            Some(diff) => {
+                format!("Output did not match the '{}' block:\n{}", EXPECT_BLOCK, diff).into()
+            }
+        };
+        self.errors.push(BlockError {
+            block: self.block,
+            span: expectation.span,
+            cause,
+        });
+    }
+
+    /// Whether executed programs start with a cleared environment, along with the
+    /// variables to set for them.
+    fn environment(&self) -> (bool, BTreeMap<&str, &str>) {
+        let mut clear = self.config.clear_env;
+        let mut vars: BTreeMap<&str, &str> = self
+            .config
+            .env
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        for (action, _) in &self.actions {
+            match *action {
+                Action::Env(ref name, ref value) => {
+                    vars.insert(name, value);
+                }
+                Action::ClearEnv => clear = true,
+                _ => {}
+            }
+        }
+        (clear, vars)
+    }
+
+    /// The time after which executed programs are killed, if any.
+    fn timeout(&self) -> Option<Duration> {
+        self.actions
+            .iter()
+            .rev()
+            .find_map(|(a, _)| match *a {
+                Action::Timeout(timeout) => Some(timeout),
+                _ => None,
+            })
+            .or(self.config.timeout)
+    }
+
+    /// The program and its arguments to execute for the given code block `program`, which
+    /// may be mapped to an interpreter in the configuration.
+    fn command_line(&self, program: &str) -> Vec<String> {
+        let mut command_line = self
+            .config
+            .interpreters
+            .get(program)
+            .cloned()
+            .unwrap_or_else(|| vec![program.to_owned()]);
+        for (action, _) in &self.actions {
+            if let Action::Args(ref args) = *action {
+                command_line.extend(args.iter().cloned());
+            }
+        }
+        command_line
+    }
+
+    /// The output to show for the current block.
+    fn output(&self) -> Output {
+        self.actions
+            .iter()
+            .rev()
+            .find_map(|(a, _)| match *a {
+                Action::Output(output) => Some(output),
+                _ => None,
+            })
+            .unwrap_or(Output::Both)
+    }
+
+    /// If true, escape sequences in the output of the current block are presented in a way
+    /// suitable for the renderer.
+    fn ansi(&self) -> bool {
+        self.actions
+            .iter()
+            .rev()
+            .find_map(|(a, _)| match *a {
+                Action::Ansi(enabled) => Some(enabled),
+                _ => None,
+            })
+            .unwrap_or(self.config.ansi)
+    }
+
+    /// The size of the pseudo-terminal to execute the current block in, if any.
+    fn tty(&self) -> Option<TtySize> {
+        self.actions
+            .iter()
+            .rev()
+            .find_map(|(a, _)| match *a {
+                Action::Tty(size) => Some(size),
+                _ => None,
+            })
+            .unwrap_or(self.config.tty)
+    }
+
+    /// The name of the session to run the code of the current block in, if any.
+    fn session(&self) -> Option<&str> {
+        self.actions.iter().rev().find_map(|(a, _)| match *a {
+            Action::Session(ref name) => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Make `command` run in a temporary directory if the current chapter is isolated.
+    ///
+    /// Each session gets its own directory, which is returned to live as long as the session.
+    /// All other programs of a chapter share one.
+    fn use_work_dir(&self, session: Option<&str>, command: &mut Command) -> Result<Option<WorkDir>> {
+        if !self.isolated {
+            return Ok(None);
+        }
+        match session {
+            Some(name) if self.sessions.borrow().contains_key(name) => Ok(None),
+            Some(_) => {
+                let work_dir = WorkDir::new(self.keep_temp_dirs)?;
+                work_dir.apply(command);
+                Ok(Some(work_dir))
+            }
+            None => {
+                let mut work_dir = self.work_dir.borrow_mut();
+                if work_dir.is_none() {
+                    *work_dir = Some(WorkDir::new(self.keep_temp_dirs)?);
+                }
+                work_dir.as_ref().expect("just initialized").apply(command);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Run the code of the current block in the session called `name`, which is started
+    /// with `command` in `work_dir` if it isn't running yet.
+    fn run_in_session(
+        &self,
+        name: &str,
+        command: &mut Command,
+        work_dir: Option<WorkDir>,
+        timeout: Option<Duration>,
+    ) -> io::Result<Completion> {
+        let mut sessions = self.sessions.borrow_mut();
+        let (session, _) = match sessions.entry(name.to_owned()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert((Session::spawn(command)?, work_dir)),
+        };
+        let output = session.run(&self.code, timeout, self.output() == Output::Interleaved)?;
+        if !session.is_alive() {
+            sessions.remove(name);
+        }
+        Ok(output)
+    }
+
+    /// The versions of the Rust compilers and the sources of path dependencies, which
+    /// compiled programs depend on.
+    fn compiler_key(&self) -> String {
+        self.compiler_key
+            .borrow_mut()
+            .get_or_insert_with(|| {
+                let mut key = KeyBuilder::new();
+                compile::add_to_key(&self.config.rust, &self.book_root, &mut key);
+                key.finish()
+            })
+            .clone()
+    }
+
+    /// Compile the code of the current block and return the path to the executable.
+    fn compile_rust(&self) -> Result<PathBuf> {
+        let mut rust_crate = self.rust_crate.borrow_mut();
+        if rust_crate.is_none() {
+            *rust_crate = Some(RustCrate::new(&self.config.rust, &self.book_root)?);
+        }
+        rust_crate
+            .as_ref()
+            .expect("crate to be initialized")
+            .compile(&self.code)
+    }
+
+    /// Run `program` with the code of the current block as its input, and return its output
+    /// if it exits with `desired_exit_status`.
+    ///
+    /// In a session, the code is sent to the session's program instead, which is started on first use.
+    fn execute(&self, program: &str, desired_exit_status: i32) -> Result<Completion> {
+        let session = self.session();
+        let compiled = program == compile::RUST && !self.config.interpreters.contains_key(program);
+        let command_line = self.command_line(program);
+        let program = if compiled {
+            if let Some(name) = session {
+                return Err(format!(
+                    "Cannot run '{}' code blocks in session '{}' as they are compiled.",
+                    program, name
+                ).into());
+            }
+            program.to_owned()
+        } else {
+            // Reads as "'bash' in session 'name'" within the quotes of the messages below.
+            match session {
+                Some(name) => format!("{}' in session '{}", command_line.join(" "), name),
+                None => command_line.join(" "),
+            }
+        };
+        let tty = self.tty();
+        if session.is_some() && tty.is_some() {
+            return Err(format!(
+                "Cannot run '{}' in a 'tty' as sessions need separate output streams.",
+                program
+            ).into());
+        }
+
+        // Keys are only computed for a cache, as that may run the compiler to get its version.
+        let cache_key = self
+            .cache
+            .as_ref()
+            .map(|_| self.cache_key(&command_line, compiled, desired_exit_status));
+        *self.previous_key.borrow_mut() = cache_key.clone();
+        // Sessions and temporary directories keep what previous blocks did, which isn't cached.
+        let use_cache = session.is_none()
+            && !self.isolated
+            && !self.has_action(|a| matches!(*a, Action::NoCache));
+        let cache = self.cache.as_ref().filter(|_| use_cache);
+        if let Some(output) = cache
+            .zip(cache_key.as_ref())
+            .and_then(|(cache, key)| cache.get(key))
+        {
+            eprintln!(
+                "{}: Reused cached output of program '{}' with '{:?}'.",
+                PREPROCESSOR_NAME, program, self.code
+            );
+            return Ok(output);
+        }
+
+        let (mut command, input) = if compiled {
+            let mut command = Command::new(self.compile_rust()?);
+            command.args(&command_line[1..]);
+            (command, &b""[..])
+        } else {
+            let mut command = Command::new(&command_line[0]);
+            command.args(&command_line[1..]);
+            (command, self.code.as_bytes())
+        };
+        let session_work_dir = self.use_work_dir(session, &mut command)?;
+        if let Some(cwd) = self.cwd() {
+            if !cwd.is_dir() {
+                return Err(format!(
+                    "Cannot execute '{}' in directory '{}' as it does not exist.",
+                    program,
+                    cwd.display()
+                ).into());
+            }
+            command.current_dir(cwd);
+        }
+
+        let (clear_env, vars) = self.environment();
+        if clear_env {
+            command.env_clear();
+        }
+        command.envs(&vars);
+        if tty.is_some() && !vars.contains_key("TERM") {
+            command.env("TERM", "xterm-256color");
+        }
+        let environment = if clear_env || !vars.is_empty() {
+            format!(
+                "\nenvironment{}: {}",
+                if clear_env { " (cleared)" } else { "" },
+                vars.iter()
+                    .map(|(k, v)| format!("{}={}", k, v))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            )
+        } else {
+            String::new()
+        };
+
+        let timeout = self.timeout();
+        let output = match session {
+            Some(name) => self.run_in_session(name, &mut command, session_work_dir, timeout),
+            None => exec::run(
+                &mut command,
+                input,
+                timeout,
+                match (tty, self.output()) {
+                    (Some(size), _) => Capture::Tty(size),
+                    (None, Output::Interleaved) => Capture::Interleaved,
+                    (None, _) => Capture::Separate,
+                },
+            ),
+        }
+        .map_err(|e| {
+            Error::from(format!(
+                "Failed to execute '{}' with error: {}{}",
+                program, e, environment
+            ))
+        })?;
+        eprintln!(
+            "{}: Executed program '{}' with '{:?}'.",
+            PREPROCESSOR_NAME, program, self.code
+        );
+
+        let status = match output.status {
+            Some(status) => status,
+            None => {
+                return Err(format!(
+                    "Killed '{}' as it did not finish within {:?}{}\nstdout: {}\nstderr: {}",
+                    program,
+                    timeout.expect("timeout to be set if there is no exit status"),
+                    environment,
+                    String::from_utf8_lossy(&output.stdout),
+                    String::from_utf8_lossy(&output.stderr),
+                ).into())
+            }
+        };
+        let actual_exit_status = status;
+        if actual_exit_status != desired_exit_status {
+            return Err(format!(
+                "After running '{}': Expected exit status '{}' to be '{}'{}\nstdout: {}\nstderr: {}",
+                program,
+                actual_exit_status,
+                desired_exit_status,
+                environment,
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr),
+            ).into());
+        }
+        if let (Some(cache), Some(key)) = (cache, cache_key) {
+            if let Err(e) = cache.put(&key, &output) {
+                eprintln!(
+                    "{}: Could not cache the output of '{}': {}",
+                    PREPROCESSOR_NAME, program, e
+                );
+            }
+        }
+        Ok(output)
+    }
+
+    /// The key of the output of the current block in the cache, which changes along with
+    /// everything the output depends on, including the blocks executed before it in the chapter.
+    fn cache_key(&self, command_line: &[String], compiled: bool, desired_exit_status: i32) -> String {
+        let mut key = KeyBuilder::new();
+        key.add("command-line", command_line.join("\0"))
+            .add("code", &self.code)
+            .add("exit-status", desired_exit_status.to_string());
+        let (clear_env, vars) = self.environment();
+        if compiled {
+            key.add("edition", &self.config.rust.edition)
+                .add(
+                    "dependencies",
+                    toml::to_string(&self.config.rust.dependencies).unwrap_or_default(),
+                )
+                .add("compiler", self.compiler_key());
+        } else if let Some(path) = find_program(&command_line[0], vars.get("PATH").copied()) {
+            key.add_file("program", &path);
+        }
+        key.add("clear-env", clear_env.to_string());
+        for (name, value) in vars {
+            key.add("env", format!("{}={}", name, value));
+        }
+        key.add(
+            "cwd",
+            self.cwd()
+                .map(|cwd| cwd.display().to_string())
+                .unwrap_or_default(),
+        )
+        .add("tty", format!("{:?}", self.tty()))
+        .add("interleaved", (self.output() == Output::Interleaved).to_string());
+        if let Some(ref previous) = *self.previous_key.borrow() {
+            key.add("previous", previous);
+        }
+        key.finish()
+    }
+}
+
+/// Convert `output` into a string ending with a newline, unless it is empty.
+fn newline_terminated(output: &[u8]) -> String {
+    let mut output = String::from_utf8_lossy(output).into_owned();
+    if !output.is_empty() && !output.ends_with('\n') {
+        output.push('\n');
+    }
+    output
+}
+
+/// The lines of the file at `path` relative to `book_root` which `selection` selects,
+/// ending with a newline.
+fn read_include(book_root: &Path, path: &Path, selection: &Selection) -> Result<String> {
+    let file_path = book_root.join(path);
+    let mut buf = String::new();
+    File::open(&file_path)
+        .map_err(|e| {
+            Error::from(e).chain_err(|| {
+                format!(
+                    "include-file={} failed as the file at '{}' could not be opened",
+                    path.display(),
+                    file_path.display()
+                )
+            })
+        })?
+        .read_to_string(&mut buf)
+        .map_err(|e| {
+            Error::from(e)
+                .chain_err(|| format!("Could not read file at '{}'", file_path.display()))
+        })?;
+    let mut buf = include::select(&buf, selection, path)?;
+    if !buf.ends_with('\n') {
+        buf.push('\n')
+    }
+    Ok(buf)
+}
+
+/// Add the `text` of a code block to its `code`, and return the text to show, which lacks
+/// the lines starting with the hidden line `prefix`.
+fn add_code(text: &str, prefix: Option<&str>, code: &mut String) -> String {
+    let prefix = match prefix {
+        Some(prefix) => prefix,
+        None => {
+            code.push_str(text);
+            return text.to_owned();
+        }
+    };
+    let mut shown = String::with_capacity(text.len());
+    for line in text.split_inclusive('\n') {
+        let content = line.trim_start();
+        let indentation = &line[..line.len() - content.len()];
+        if let Some(hidden) = content.strip_prefix(prefix) {
+            code.push_str(indentation);
+            code.push_str(hidden);
+        } else if content.trim_end() == prefix.trim_end() {
+            // Like with rustdoc, a prefix of `# ` also hides lines consisting of `#` alone.
+            code.push_str(indentation);
+            code.push_str(&content[prefix.trim_end().len()..]);
+        } else {
+            code.push_str(line);
+            shown.push_str(line);
+        }
     }
+    shown
 }
 
-fn parse_actions(info: &str) -> Result<Vec<Action>> {
+/// Parse the actions from the `info` string of a code block, which starts at byte `offset`
+/// of the chapter.
+///
+/// Each action is returned along with the byte range of its tag within the chapter, which is also
+/// provided for the tag that failed to parse.
+#[allow(clippy::result_large_err)]
+fn parse_actions(
+    info: &str,
+    offset: usize,
+) -> std::result::Result<Vec<TaggedAction>, (Range<usize>, Error)> {
+    let (program, tags) = split_info(info, offset);
     let mut res = Vec::new();
-    let mut shell = "bash";
+    for tag in tags {
+        if let Some(action) =
+            Action::from_str(program, tag.key, tag.value).map_err(|err| (tag.span.clone(), err))?
+        {
+            res.push((action, tag.span));
+        }
+    }
+    Ok(res)
+}
+
+/// A tag of a code block, like `key` or `key=value`.
+struct InfoTag<'a> {
+    key: &'a str,
+    value: Option<&'a str>,
+    /// The byte range of the tag within the chapter.
+    span: Range<usize>,
+}
+
+/// Split the `info` string of a code block, which starts at byte `offset` of the chapter,
+/// into the program and the tags following it.
+fn split_info(info: &str, offset: usize) -> (&str, Vec<InfoTag<'_>>) {
+    let mut program = "bash";
+    let mut tags = Vec::new();
+    let mut token_offset = offset + (info.len() - info.trim_start().len());
     for (tid, token) in info.trim().split(',').enumerate() {
+        let key_offset = token_offset + (token.len() - token.trim_start().len());
+        let span = key_offset..key_offset + token.trim().len();
+        token_offset += token.len() + 1;
         if tid == 0 {
-            shell = token;
+            program = token;
         } else {
             let mut kvi = token.splitn(2, '=');
-            let optional_action = match (kvi.next().map(str::trim), kvi.next().map(str::trim)) {
-                (Some(key), possible_value) => Action::from_str(shell, key, possible_value)?,
-                _ => None,
-            };
-            if let Some(action) = optional_action {
-                res.push(action);
+            if let Some(key) = kvi.next().map(str::trim) {
+                tags.push(InfoTag {
+                    key,
+                    value: kvi.next().map(str::trim),
+                    span,
+                });
             }
         }
     }
-    Ok(res)
+    (program, tags)
+}
+
+/// Return an error for the unknown tag `key`, suggesting the known tag closest to it.
+fn unknown_tag(key: &str) -> Error {
+    let closest = TAGS
+        .iter()
+        .map(|tag| (strsim::damerau_levenshtein(key, tag), tag))
+        .min()
+        .filter(|(distance, _)| *distance <= 2);
+    let suggestion = match closest {
+        Some((_, tag)) => format!(", did you mean '{}'?", tag),
+        None => ".".into(),
+    };
+    format!(
+        "Unknown tag '{}'{} Tags used by other preprocessors or renderers can be added to \
+         'allowed-tags' in [preprocessor.{}].",
+        key, suggestion, PREPROCESSOR_NAME
+    ).into()
 }
 
-#[cfg_attr(feature = "cargo-clippy", allow(clippy::needless_pass_by_value))]
+/// Return the byte offset of the given `info` string of the code block starting at byte `start` of
+/// the chapter's `content`, or the offset of the code block itself if it can't be found.
+fn info_offset(content: &str, start: usize, info: &str) -> usize {
+    let fence = content[start..].lines().next().unwrap_or("");
+    let info = info.trim();
+    if info.is_empty() {
+        return start;
+    }
+    fence.find(info).map_or(start, |pos| start + pos)
+}
+
+/// The end of the `<details>` element around collapsed output.
+const DETAILS_END: &str = "</details>\n\n";
+
+/// The start of the `<details>` element around collapsed output, with the given `summary`.
+fn details_start(summary: &str) -> Event<'static> {
+    let mut html = String::from("<details>\n<summary>");
+    ansi::escape_html(summary, &mut html);
+    html.push_str("</summary>\n\n");
+    Event::Html(html.into())
+}
+
+/// Process the `event` at the byte `range` of the chapter's `content`, and return the events
+/// to replace it with. If `expect_follows` is true, the next event starts an `expect-output` block.
+#[allow(clippy::needless_pass_by_value)]
 fn event_filter<'a>(
-    state: &mut &mut State,
+    state: &mut State,
+    content: &str,
     event: Event<'a>,
+    range: Range<usize>,
+    expect_follows: bool,
     dry_run: bool,
 ) -> Option<Vec<Event<'a>>> {
     use pulldown_cmark::Event::*;
@@ -251,30 +1728,107 @@ fn event_filter<'a>(
 
     let mut res = vec![event.clone()];
     let hide = match event {
+        Start(CodeBlock(ref info)) if is_expect_block(info) => {
+            state.block += 1;
+            state.in_expect_block = true;
+            let info_start = info_offset(content, range.start, info);
+            let expectation = state.expectation.get_or_insert_with(Expectation::default);
+            expectation.span = info_start..info_start + EXPECT_BLOCK.len();
+            // The expected output is shown in place of the actual one, which matches it.
+            res = vec![Start(CodeBlock("output".into()))];
+            if let Some(ref summary) = expectation.summary {
+                res.insert(0, details_start(summary));
+            }
+            expectation.hide
+        }
+        Text(ref text) if state.in_expect_block => {
+            let expectation = state.expectation.as_mut().expect("set when the block started");
+            expectation.expected.push_str(text);
+            expectation.hide
+        }
+        End(CodeBlock(_)) if state.in_expect_block => {
+            state.in_expect_block = false;
+            res = vec![End(CodeBlock("output".into()))];
+            if state.expectation.as_ref().is_some_and(|e| e.summary.is_some()) {
+                res.push(Html(DETAILS_END.into()));
+            }
+            let hide = state.expectation.as_ref().is_some_and(|e| e.hide);
+            state.check_expectation();
+            hide
+        }
         Start(CodeBlock(ref info)) => {
-            state.actions = match parse_actions(info) {
+            state.block += 1;
+            state.actions = match parse_actions(info, info_offset(content, range.start, info)) {
                 Ok(a) => a,
-                Err(e) => {
-                    state.error = Some(e);
+                Err((span, cause)) => {
+                    state.errors.push(BlockError {
+                        block: state.block,
+                        span,
+                        cause,
+                    });
                     Vec::new()
                 }
             };
-            state.should_hide()
+            if state.has_action(|a| matches!(*a, Action::Exec { .. })) {
+                state.exec_block += 1;
+            }
+            let offset = info_offset(content, range.start, info);
+            for (span, cause) in state.check_unknown_tags(info, offset) {
+                state.errors.push(BlockError {
+                    block: state.block,
+                    span,
+                    cause,
+                });
+            }
+            if let Some((span, cause)) = state.check_redaction_names() {
+                state.errors.push(BlockError {
+                    block: state.block,
+                    span,
+                    cause,
+                });
+            }
+            state.hides_code()
         }
         Text(ref text) => {
             if state.is_in_marked_codeblock() {
-                state.code.push_str(text);
+                let prefix = state.config.hidden_line_prefix.as_deref();
+                let shown = add_code(text, prefix, &mut state.code);
+                res = vec![Text(shown.into())];
             }
-            state.should_hide()
+            state.hides_code()
         }
         End(CodeBlock(_)) => {
+            if expect_follows {
+                state.expectation = Some(Expectation {
+                    follows_exec: state.has_action(|a| matches!(*a, Action::Exec { .. })),
+                    hide: state.hides_output(),
+                    summary: state.collapse_summary(),
+                    ..Expectation::default()
+                });
+            }
             state.apply_end_of_codeblock_actions(&mut res, dry_run);
-            let hide = state.should_hide();
+            // The events after the end of the code block show its output.
+            let code_end = res
+                .iter()
+                .position(|e| matches!(*e, End(CodeBlock(_))))
+                .expect("end of the code block to be kept");
+            let mut output = res.split_off(code_end + 1);
+            if state.hides_code() {
+                res.clear();
+            }
+            if state.hides_output() {
+                output.clear();
+            }
+            if let (false, Some(summary)) = (output.is_empty(), state.collapse_summary()) {
+                output.insert(0, details_start(&summary));
+                output.push(Html(DETAILS_END.into()));
+            }
+            res.extend(output);
             state.actions.clear();
             state.code.clear();
-            hide
+            false
         }
-        _ => state.should_hide(),
+        _ => state.hides_code(),
     };
     if hide {
         res.clear();
@@ -286,57 +1840,307 @@ fn process_chapter(
     ctx: &PreprocessorContext,
     chapter: &mut Chapter,
     state: &mut State,
+    diagnostics: &mut Diagnostics,
     dry_run: bool,
 ) -> Result<()> {
     state.book_root = ctx.root.clone();
+    state.block = 0;
+    state.exec_block = 0;
+    state.chapter_path = chapter.path.clone();
+    state.previous_key.borrow_mut().take();
+    state.isolated = !dry_run && state.config.temp_dirs.is_enabled_for(chapter);
 
     let md = {
         let mut md = String::with_capacity(chapter.content.len() + 128);
         {
-            let parser = Parser::new(&chapter.content)
-                .scan(state, |s, e| event_filter(s, e, dry_run))
-                .flatten();
+            let content = &chapter.content;
+            let mut events = Parser::new(content).into_offset_iter().peekable();
+            let parser = std::iter::from_fn(|| {
+                let (event, range) = events.next()?;
+                let expect_follows = matches!(
+                    events.peek(),
+                    Some((Event::Start(Tag::CodeBlock(info)), _)) if is_expect_block(info)
+                );
+                event_filter(state, content, event, range, expect_follows, dry_run)
+            })
+            .flatten();
             cmark(parser, &mut md, None).map_err(|e| format!("{}", e))?;
         }
         md
     };
+    state.work_dir.borrow_mut().take();
+    diagnostics.0.extend(
+        state
+            .errors
+            .drain(..)
+            .map(|e| Diagnostic::new(chapter, e.block, e.span, e.cause)),
+    );
     chapter.content = md;
 
     Ok(())
 }
 
+/// Process all chapters in `items` in book order, descending into sub-chapters
+/// right after their parent.
+fn process_items(
+    ctx: &PreprocessorContext,
+    items: &mut [BookItem],
+    globs: &GlobSet,
+    state: &mut State,
+    diagnostics: &mut Diagnostics,
+    amount_of_included_chapters: &mut usize,
+) -> Result<()> {
+    for item in items.iter_mut() {
+        if let BookItem::Chapter(ref mut chapter) = *item {
+            let dry_run = exclude_chapter(globs, chapter);
+            if !dry_run {
+                *amount_of_included_chapters += 1;
+            }
+
+            process_chapter(ctx, chapter, state, diagnostics, dry_run)?;
+
+            process_items(
+                ctx,
+                &mut chapter.sub_items,
+                globs,
+                state,
+                diagnostics,
+                amount_of_included_chapters,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Move all chapters in `items` into `chapters` in book order, without their sub-chapters
+/// and leaving their content empty.
+fn take_chapters(items: &mut [BookItem], chapters: &mut Vec<Chapter>) {
+    for item in items.iter_mut() {
+        if let BookItem::Chapter(ref mut chapter) = *item {
+            chapters.push(Chapter {
+                name: chapter.name.clone(),
+                content: std::mem::take(&mut chapter.content),
+                number: chapter.number.clone(),
+                sub_items: Vec::new(),
+                path: chapter.path.clone(),
+                parent_names: chapter.parent_names.clone(),
+            });
+            take_chapters(&mut chapter.sub_items, chapters);
+        }
+    }
+}
+
+/// Put the content of `chapters` back into the chapters of `items` they were taken from.
+fn restore_chapters(items: &mut [BookItem], chapters: &mut impl Iterator<Item = Chapter>) {
+    for item in items.iter_mut() {
+        if let BookItem::Chapter(ref mut chapter) = *item {
+            chapter.content = chapters.next().expect("a chapter for each taken one").content;
+            restore_chapters(&mut chapter.sub_items, chapters);
+        }
+    }
+}
+
+/// The names of all sessions used by code blocks in `content`.
+fn session_names(content: &str) -> BTreeSet<String> {
+    Parser::new(content)
+        .filter_map(|event| match event {
+            Event::Start(Tag::CodeBlock(info)) => parse_actions(&info, 0).ok(),
+            _ => None,
+        })
+        .flatten()
+        .filter_map(|(action, _)| match action {
+            Action::Session(name) => Some(name),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Split `chapters` into groups of chapter indices which can be processed independently
+/// of each other, as they don't share any session. Groups and their chapters are in book order.
+fn independent_groups(chapters: &[Chapter]) -> Vec<Vec<usize>> {
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut group_by_session: HashMap<String, usize> = HashMap::new();
+    for (index, chapter) in chapters.iter().enumerate() {
+        let sessions = session_names(&chapter.content);
+        let mut shared: Vec<usize> = sessions
+            .iter()
+            .filter_map(|name| group_by_session.get(name).copied())
+            .collect();
+        shared.sort_unstable();
+        shared.dedup();
+        let group = match shared.split_first() {
+            Some((&first, others)) => {
+                for &other in others {
+                    let merged = std::mem::take(&mut groups[other]);
+                    groups[first].extend(merged);
+                    for group in group_by_session.values_mut() {
+                        if *group == other {
+                            *group = first;
+                        }
+                    }
+                }
+                groups[first].push(index);
+                groups[first].sort_unstable();
+                first
+            }
+            None => {
+                groups.push(vec![index]);
+                groups.len() - 1
+            }
+        };
+        for name in sessions {
+            group_by_session.insert(name, group);
+        }
+    }
+    groups.retain(|group| !group.is_empty());
+    groups
+}
+
+impl RunCodeBlocks {
+    fn state(&self, ctx: &PreprocessorContext, prepare: Prepared) -> State {
+        State {
+            prepare,
+            config: self.config.clone(),
+            keep_temp_dirs: self.keep_temp_dirs,
+            update_snapshots: self.update_snapshots,
+            cache: if self.cache && self.config.cache {
+                Some(Cache::new(&ctx.root))
+            } else {
+                None
+            },
+            ansi_rendering: AnsiRendering::for_renderer(&ctx.renderer),
+            collapsible: ctx.renderer == "html",
+            ..State::default()
+        }
+    }
+
+    /// Find the `prepare` blocks of all `chapters` without executing anything, so code
+    /// blocks can use blocks defined after them.
+    fn prepared_blocks<'a>(
+        &self,
+        ctx: &PreprocessorContext,
+        chapters: impl Iterator<Item = &'a Chapter>,
+    ) -> Result<Prepared> {
+        let mut state = self.state(ctx, Prepared::default());
+        state.resolving = true;
+        for chapter in chapters {
+            // Errors are reported when processing the chapter.
+            let mut ignored = Diagnostics::default();
+            process_chapter(ctx, &mut chapter.clone(), &mut state, &mut ignored, true)?;
+        }
+        Ok(state.prepare)
+    }
+
+    /// Process the chapters of `book` on up to `jobs` threads, and return the diagnostics
+    /// of all chapters in book order along with the amount of chapters matching `globs`.
+    fn process_in_parallel(
+        &self,
+        ctx: &PreprocessorContext,
+        book: &mut Book,
+        globs: &GlobSet,
+        jobs: usize,
+    ) -> Result<(Diagnostics, usize)> {
+        let mut chapters = Vec::new();
+        take_chapters(&mut book.sections, &mut chapters);
+
+        let prepared = self.prepared_blocks(ctx, chapters.iter())?;
+        let groups = independent_groups(&chapters);
+        let amount_of_included_chapters = chapters
+            .iter()
+            .filter(|chapter| !exclude_chapter(globs, chapter))
+            .count();
+        let chapters: Vec<_> = chapters.into_iter().map(Mutex::new).collect();
+        let next_group = AtomicUsize::new(0);
+        let mut outcomes = thread::scope(|scope| {
+            let workers: Vec<_> = (0..jobs.min(groups.len()))
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut state = self.state(ctx, prepared.clone());
+                        let mut outcomes = Vec::new();
+                        let take_group = || groups.get(next_group.fetch_add(1, Ordering::SeqCst));
+                        while let Some(group) = take_group() {
+                            for &index in group {
+                                let mut chapter = chapters[index].lock().expect("no poisoning");
+                                let mut diagnostics = Diagnostics::default();
+                                let dry_run = exclude_chapter(globs, &chapter);
+                                let outcome = process_chapter(
+                                    ctx,
+                                    &mut chapter,
+                                    &mut state,
+                                    &mut diagnostics,
+                                    dry_run,
+                                );
+                                outcomes.push((index, outcome.map(|_| diagnostics)));
+                            }
+                        }
+                        outcomes
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| {
+                    worker
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                })
+                .collect::<Vec<_>>()
+        });
+        outcomes.sort_by_key(|(index, _)| *index);
+
+        let mut diagnostics = Diagnostics::default();
+        for (_, outcome) in outcomes {
+            diagnostics.0.extend(outcome?.0);
+        }
+        restore_chapters(
+            &mut book.sections,
+            &mut chapters
+                .into_iter()
+                .map(|chapter| chapter.into_inner().expect("no poisoning")),
+        );
+        Ok((diagnostics, amount_of_included_chapters))
+    }
+}
+
 impl Preprocessor for RunCodeBlocks {
     fn name(&self) -> &str {
         PREPROCESSOR_NAME
     }
 
     fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book> {
-        let mut state = State::default();
         let globs = globset_from_strings(&self.globs)?;
-        let mut amount_of_included_chapters = 0;
-        for item in book.sections.iter_mut() {
-            if let BookItem::Chapter(ref mut chapter) = *item {
-                let dry_run = exclude_chapter(&globs, chapter);
-                if !dry_run {
-                    amount_of_included_chapters += 1;
-                }
-
-                process_chapter(ctx, chapter, &mut state, dry_run)?;
-                state.error = state.error.map(|err| {
-                    err.chain_err(|| {
-                        format!(
-                            "{}: Preprocessing failed for chapter '{}' in file '{}'.",
-                            PREPROCESSOR_NAME,
-                            chapter.name,
-                            chapter.path.display()
-                        )
-                    })
-                });
-            }
+        let jobs = match self.jobs {
+            0 => thread::available_parallelism().map_or(1, usize::from),
+            jobs => jobs,
+        };
+        let (diagnostics, amount_of_included_chapters) = if jobs > 1 {
+            self.process_in_parallel(ctx, &mut book, &globs, jobs)?
+        } else {
+            let chapters = book.iter().filter_map(|item| match *item {
+                BookItem::Chapter(ref chapter) => Some(chapter),
+                _ => None,
+            });
+            let mut state = self.state(ctx, self.prepared_blocks(ctx, chapters)?);
+            let mut diagnostics = Diagnostics::default();
+            let mut amount_of_included_chapters = 0;
+            process_items(
+                ctx,
+                &mut book.sections,
+                &globs,
+                &mut state,
+                &mut diagnostics,
+                &mut amount_of_included_chapters,
+            )?;
+            (diagnostics, amount_of_included_chapters)
         };
 
-        if let Some(error) = state.error {
-            return Err(error);
+        if !diagnostics.0.is_empty() {
+            let message = format!(
+                "{}: Preprocessing failed for {} code block(s).",
+                PREPROCESSOR_NAME,
+                diagnostics.0.len()
+            );
+            return Err(Error::with_chain(diagnostics, message));
         }
         if !globs.is_empty() && amount_of_included_chapters == 0 {
             return Err("globs did not match any chapter.".into());
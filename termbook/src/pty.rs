@@ -0,0 +1,46 @@
+use crate::config::TtySize;
+
+use std::{
+    fs::File,
+    io, mem,
+    os::unix::io::{AsRawFd, FromRawFd},
+    ptr,
+};
+
+/// Open a pseudo-terminal of the given `size`, and return its master and slave end.
+///
+/// Newlines written to the slave are not translated into carriage-return and newline,
+/// so that the output doesn't differ from the one captured through a pipe.
+pub(crate) fn open(size: TtySize) -> io::Result<(File, File)> {
+    let winsize = libc::winsize {
+        ws_row: size.rows,
+        ws_col: size.cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    let (mut master, mut slave) = (0, 0);
+    unsafe {
+        if libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null(), &winsize) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+    for file in &[&master, &slave] {
+        check(unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) })?;
+    }
+    unsafe {
+        let mut termios: libc::termios = mem::zeroed();
+        check(libc::tcgetattr(slave.as_raw_fd(), &mut termios))?;
+        termios.c_oflag &= !libc::ONLCR;
+        check(libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios))?;
+    }
+    Ok((master, slave))
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+use crate::exec::TEMP_DIR_VAR;
+
+use regex::bytes::{NoExpand, Regex};
+
+use std::{borrow::Cow, path::PathBuf};
+
+/// The text replacing matches of rules without a replacement.
+pub(crate) const DEFAULT_REPLACEMENT: &str = "[redacted]";
+
+/// A rule replacing all matches of a regular expression in the output of programs.
+#[derive(Clone)]
+pub(crate) struct Rule {
+    /// The name to select the rule with in `redact` tags.
+    pub name: Option<String>,
+    pub regex: Regex,
+    /// The text replacing each match, which may refer to capture groups like `$1`.
+    pub replacement: String,
+}
+
+/// A built-in rule replacing a path which is different on every machine.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Preset {
+    /// The temporary directory the program was executed in.
+    TempDir,
+    /// The directory containing the book.
+    BookRoot,
+    /// The home directory of the user.
+    Home,
+}
+
+impl Preset {
+    pub const ALL: [Preset; 3] = [Preset::TempDir, Preset::BookRoot, Preset::Home];
+
+    pub fn from_name(name: &str) -> Option<Preset> {
+        Preset::ALL.iter().copied().find(|preset| preset.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Preset::TempDir => "temp-dir",
+            Preset::BookRoot => "book-root",
+            Preset::Home => "home",
+        }
+    }
+
+    pub fn replacement(self) -> Cow<'static, str> {
+        match self {
+            Preset::TempDir => format!("${}", TEMP_DIR_VAR).into(),
+            Preset::BookRoot => ".".into(),
+            Preset::Home => "~".into(),
+        }
+    }
+}
+
+/// Replace all occurrences of the given `paths` in `output` with their replacement,
+/// followed by all matches of the `rules`.
+///
+/// Paths are replaced longest first, as they may contain each other, both as given and
+/// with symbolic links resolved.
+pub(crate) fn apply(output: &[u8], paths: &[(PathBuf, Cow<str>)], rules: &[&Rule]) -> Vec<u8> {
+    let mut literals: Vec<(String, &str)> = Vec::new();
+    for (path, replacement) in paths {
+        let canonical = path.canonicalize().ok();
+        for path in Some(path.as_path()).into_iter().chain(canonical.as_deref()) {
+            let path = path.to_string_lossy().into_owned();
+            if !path.is_empty() && !literals.iter().any(|(known, _)| *known == path) {
+                literals.push((path, replacement));
+            }
+        }
+    }
+    literals.sort_by_key(|(path, _)| std::cmp::Reverse(path.len()));
+
+    let mut output = output.to_owned();
+    for (path, replacement) in literals {
+        let regex = Regex::new(&regex::escape(&path)).expect("escaped text to be valid");
+        output = regex.replace_all(&output, NoExpand(replacement.as_bytes())).into_owned();
+    }
+    for rule in rules {
+        output = rule
+            .regex
+            .replace_all(&output, rule.replacement.as_bytes())
+            .into_owned();
+    }
+    output
+}
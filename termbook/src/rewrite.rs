@@ -25,12 +25,13 @@ impl Renderer for Rewrite {
         for item in ctx.book.iter() {
             if let BookItem::Chapter(ref chapter) = *item {
                 let dir = ctx.destination
-                    .join(&chapter.path.parent().expect("at least filename"));
+                    .join(chapter.path.parent().expect("at least filename"));
                 create_dir_all(&dir)?;
-                let output_file = dir.join(&chapter.path.file_name().expect("a filename to be present"));
+                let output_file = dir.join(chapter.path.file_name().expect("a filename to be present"));
                 let mut fout = OpenOptions::new()
                     .write(true)
                     .create(true)
+                    .truncate(true)
                     .open(&output_file)?;
                 fout.write_all(chapter.content.as_bytes())?;
                 eprintln!("Wrote markdown file at '{}'.", chapter.path.display());
@@ -0,0 +1,211 @@
+use crate::exec::{self, Completion};
+
+use std::{
+    io::{self, Read, Write},
+    process::{Child, ChildStdin, Command},
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// A long-lived shell process which executes the code of multiple code blocks.
+///
+/// After each block, the shell is asked to print a sentinel line to both stdout and stderr,
+/// the one on stdout carrying the exit status of the block. This is how the output of
+/// the individual blocks is told apart, which is why the shell must understand `printf` and `$?`.
+pub(crate) struct Session {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    chunks: Receiver<(Stream, Vec<u8>)>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    open_streams: usize,
+    sentinel: String,
+    is_alive: bool,
+}
+
+impl Session {
+    pub fn spawn(command: &mut Command) -> io::Result<Session> {
+        exec::configure(command);
+        let mut child = command.spawn()?;
+        let (tx, rx) = channel();
+        forward(Stream::Stdout, child.stdout.take().expect("stdout to be configured"), tx.clone());
+        forward(Stream::Stderr, child.stderr.take().expect("stderr to be configured"), tx);
+        Ok(Session {
+            sentinel: format!("__termbook_session_{}_done__", child.id()),
+            stdin: child.stdin.take(),
+            child,
+            chunks: rx,
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            open_streams: 2,
+            is_alive: true,
+        })
+    }
+
+    /// If false, the shell exited or was killed, and can't run any more code.
+    pub fn is_alive(&self) -> bool {
+        self.is_alive
+    }
+
+    /// Run `code` in the shell and return its output, and the exit status of its last command.
+    ///
+    /// If the shell exits while running `code`, its exit status is returned instead.
+    /// If `timeout` elapses, the shell is killed along with all processes it started.
+    /// If `interleave` is true, the shell redirects stderr of `code` to stdout, which
+    /// keeps the order in which both were written.
+    pub fn run(
+        &mut self,
+        code: &str,
+        timeout: Option<Duration>,
+        interleave: bool,
+    ) -> io::Result<Completion> {
+        let code = if interleave {
+            format!("{{\n{}\n}} 2>&1", code)
+        } else {
+            code.to_owned()
+        };
+        let script = format!(
+            "{code}\n__termbook_status=$?\n\
+             printf '\\n%s %d\\n' '{sentinel}' \"$__termbook_status\"\n\
+             printf '\\n%s\\n' '{sentinel}' >&2\n",
+            code = code,
+            sentinel = self.sentinel
+        );
+        if let Some(stdin) = self.stdin.as_mut() {
+            // A shell that exited is detected when reading its output.
+            stdin.write_all(script.as_bytes()).and_then(|_| stdin.flush()).ok();
+        }
+
+        // A timeout too large to ever elapse is the same as none.
+        let deadline = timeout.and_then(|t| Instant::now().checked_add(t));
+        loop {
+            if let Some(completion) = self.take_completed_block() {
+                return Ok(completion);
+            }
+            if self.open_streams == 0 {
+                self.is_alive = false;
+                let status = exec::exit_code(self.child.wait()?);
+                return Ok(self.drain(Some(status)));
+            }
+
+            let received = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        Err(RecvTimeoutError::Timeout)
+                    } else {
+                        self.chunks.recv_timeout(deadline - now)
+                    }
+                }
+                None => self.chunks.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok((_, ref chunk)) if chunk.is_empty() => self.open_streams -= 1,
+                Ok((Stream::Stdout, chunk)) => self.stdout.extend_from_slice(&chunk),
+                Ok((Stream::Stderr, chunk)) => self.stderr.extend_from_slice(&chunk),
+                Err(RecvTimeoutError::Disconnected) => self.open_streams = 0,
+                Err(RecvTimeoutError::Timeout) => {
+                    self.kill();
+                    return Ok(self.drain(None));
+                }
+            }
+        }
+    }
+
+    /// Remove the output of the current block from the buffers once both sentinels were seen,
+    /// and return it along with the block's exit status.
+    fn take_completed_block(&mut self) -> Option<Completion> {
+        let stdout_marker = format!("\n{} ", self.sentinel);
+        let stderr_marker = format!("\n{}\n", self.sentinel);
+        let stdout_end = find(&self.stdout, stdout_marker.as_bytes())?;
+        let status_start = stdout_end + stdout_marker.len();
+        let status_end =
+            status_start + self.stdout[status_start..].iter().position(|b| *b == b'\n')?;
+        let stderr_end = find(&self.stderr, stderr_marker.as_bytes())?;
+
+        let status = String::from_utf8_lossy(&self.stdout[status_start..status_end])
+            .trim()
+            .parse()
+            .unwrap_or(1);
+        let stdout = self.stdout[..stdout_end].to_owned();
+        self.stdout.drain(..=status_end);
+        let stderr = self.stderr[..stderr_end].to_owned();
+        self.stderr.drain(..stderr_end + stderr_marker.len());
+        Some(Completion {
+            status: Some(status),
+            stdout,
+            stderr,
+        })
+    }
+
+    /// Collect all remaining output of a shell which is about to exit or was killed.
+    fn drain(&mut self, status: Option<i32>) -> Completion {
+        while self.open_streams > 0 {
+            match self.chunks.recv_timeout(Duration::from_secs(1)) {
+                Ok((_, ref chunk)) if chunk.is_empty() => self.open_streams -= 1,
+                Ok((Stream::Stdout, chunk)) => self.stdout.extend_from_slice(&chunk),
+                Ok((Stream::Stderr, chunk)) => self.stderr.extend_from_slice(&chunk),
+                Err(_) => break,
+            }
+        }
+        Completion {
+            status,
+            stdout: std::mem::take(&mut self.stdout),
+            stderr: std::mem::take(&mut self.stderr),
+        }
+    }
+
+    fn kill(&mut self) {
+        self.is_alive = false;
+        exec::kill_process_group(self.child.id());
+        self.child.kill().ok();
+        self.child.wait().ok();
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if self.is_alive {
+            // Closing stdin makes the shell exit once it is done.
+            self.stdin.take();
+            self.child.wait().ok();
+        }
+        // Processes started in the background would otherwise outlive the build.
+        exec::kill_process_group(self.child.id());
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn forward<R>(stream: Stream, mut reader: R, tx: Sender<(Stream, Vec<u8>)>)
+where
+    R: Read + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut chunk = [0u8; 4096];
+        loop {
+            match reader.read(&mut chunk) {
+                Ok(0) | Err(_) => {
+                    tx.send((stream, Vec::new())).ok();
+                    break;
+                }
+                Ok(n) => {
+                    if tx.send((stream, chunk[..n].to_owned())).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
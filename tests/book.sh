@@ -136,6 +136,39 @@ EOF
           expect_snapshot "$snapshot/include-file-existing-executable" "$OUTPUT_DIR/markdown-rewrite"
         }
       )
+
+      (with "line ranges and anchors"
+        make-book "$fixture/books/include-file-lines.md"
+        cat <<'EOF' > "$BOOK/main.rs"
+// ANCHOR: all
+fn main() {
+    // ANCHOR: greet
+    println!("hello");
+    // ANCHOR_END: greet
+}
+// ANCHOR_END: all
+EOF
+        printf "exit 1\necho 'only the second line'\nexit 1\n" > "$BOOK/script.sh"
+
+        it "succeeds" && {
+          expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+        }
+
+        it "includes only the selected lines, without anchors" && {
+          expect_snapshot "$snapshot/include-file-lines" "$OUTPUT_DIR/markdown-rewrite"
+        }
+
+        (when "they are invalid or don't exist"
+          main_rs="$BOOK/main.rs"
+          make-book "$fixture/books/include-file-lines-failure.md"
+          cp "$main_rs" "$BOOK/"
+
+          it "fails with an error for each" && {
+            WITH_SNAPSHOT="$snapshot/include-file-lines-failure" \
+            expect_run $WITH_FAILURE "${args[@]}" "$BOOK"
+          }
+        )
+      )
     )
 
     (with "no specifically marked code blocks"
@@ -200,6 +233,432 @@ EOF
           expect_run $WITH_FAILURE "${args[@]}" "$BOOK"
         }
       )
+
+      (with "a 'cwd' tag and a default directory in book.toml"
+        copy-book "$fixture/books/cwd-tag-and-default"
+
+        it "succeeds" && {
+          expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+        }
+
+        it "executes the programs in the configured directories" && {
+          expect_snapshot "$snapshot/book-cwd-tag-and-default" "$OUTPUT_DIR/markdown-rewrite"
+        }
+      )
+
+      (with "a 'cwd' tag pointing to a non-existing directory"
+        make-book "$fixture/books/cwd-non-existing.md"
+
+        it "fails" && {
+          WITH_SNAPSHOT="$snapshot/cwd-non-existing" \
+          expect_run $WITH_FAILURE "${args[@]}" "$BOOK"
+        }
+      )
+
+      (with "'env' tags and environment variables in book.toml"
+        copy-book "$fixture/books/env-tags-and-config"
+
+        it "succeeds" && {
+          expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+        }
+
+        it "executes the programs with the configured environment" && {
+          expect_snapshot "$snapshot/book-env-tags-and-config" "$OUTPUT_DIR/markdown-rewrite"
+        }
+      )
+
+      (with "'env' tags on a failing block"
+        make-book "$fixture/books/env-failure.md"
+
+        it "fails and shows the environment" && {
+          WITH_SNAPSHOT="$snapshot/env-failure" \
+          expect_run $WITH_FAILURE "${args[@]}" "$BOOK"
+        }
+      )
+
+      (with "a 'timeout' tag that is exceeded"
+        make-book "$fixture/books/timeout-exceeded.md"
+
+        it "fails and shows the partial output" && {
+          WITH_SNAPSHOT="$snapshot/timeout-exceeded" \
+          expect_run $WITH_FAILURE "${args[@]}" "$BOOK"
+        }
+      )
+
+      (with "a 'timeout' tag and a process left running in the background"
+        make-book "$fixture/books/timeout-background.md"
+
+        it "fails once the timeout elapses as the process keeps the output open" && {
+          WITH_SNAPSHOT="$snapshot/timeout-background" \
+          expect_run $WITH_FAILURE "${args[@]}" "$BOOK"
+        }
+      )
+
+      (with "a 'timeout' tag too long to ever elapse"
+        make-book "$fixture/books/timeout-unreachable.md"
+
+        it "succeeds" && {
+          expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+        }
+      )
+
+      (with "invalid 'timeout' tags"
+        make-book "$fixture/books/timeout-invalid.md"
+
+        it "fails for each" && {
+          WITH_SNAPSHOT="$snapshot/timeout-invalid" \
+          expect_run $WITH_FAILURE "${args[@]}" "$BOOK"
+        }
+      )
+
+      (with "'session' tags"
+        make-book "$fixture/books/session.md"
+
+        it "succeeds" && {
+          expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+        }
+
+        it "runs blocks of the same session in one shell" && {
+          expect_snapshot "$snapshot/book-session" "$OUTPUT_DIR/markdown-rewrite"
+        }
+      )
+
+      (with "'session' tags on blocks starting processes in the background"
+        with_program pgrep
+        make-book "$fixture/books/session-background.md"
+
+        it "succeeds" && {
+          expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+        }
+
+        it "leaves no processes behind" && {
+          expect_run $WITH_FAILURE pgrep -x -f 'sleep 7777'
+        }
+      )
+
+      (with "'session' tags on failing blocks"
+        make-book "$fixture/books/session-failure.md"
+
+        it "fails for each failing block" && {
+          WITH_SNAPSHOT="$snapshot/session-failure" \
+          expect_run $WITH_FAILURE "${args[@]}" "$BOOK"
+        }
+      )
+
+      (with "'output' tags"
+        make-book "$fixture/books/output-tags.md"
+
+        it "succeeds" && {
+          expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+        }
+
+        it "shows only the selected output" && {
+          expect_snapshot "$snapshot/book-output-tags" "$OUTPUT_DIR/markdown-rewrite"
+        }
+      )
+
+      (with "an invalid 'output' tag"
+        make-book "$fixture/books/output-invalid.md"
+
+        it "fails" && {
+          WITH_SNAPSHOT="$snapshot/output-invalid" \
+          expect_run $WITH_FAILURE "${args[@]}" "$BOOK"
+        }
+      )
+
+      (with "'tty' tags"
+        make-book "$fixture/books/tty-tags.md"
+
+        it "succeeds" && {
+          expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+        }
+
+        it "executes the programs in a terminal of the given size" && {
+          expect_snapshot "$snapshot/book-tty-tags" "$OUTPUT_DIR/markdown-rewrite"
+        }
+      )
+
+      (with "invalid 'tty' tags"
+        make-book "$fixture/books/tty-failure.md"
+
+        it "fails" && {
+          WITH_SNAPSHOT="$snapshot/tty-failure" \
+          expect_run $WITH_FAILURE "${args[@]}" "$BOOK"
+        }
+      )
+
+      (with "a 'tty' default in book.toml"
+        copy-book "$fixture/books/tty-default"
+
+        it "succeeds" && {
+          expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+        }
+
+        it "executes the programs in a terminal unless turned off" && {
+          expect_snapshot "$snapshot/book-tty-default" "$OUTPUT_DIR/markdown-rewrite"
+        }
+      )
+
+      (with "'ansi' tags"
+        make-book "$fixture/books/ansi-tags.md"
+
+        it "succeeds" && {
+          expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+        }
+
+        it "strips escape sequences for the markdown output" && {
+          expect_snapshot "$snapshot/book-ansi-tags" "$OUTPUT_DIR/markdown-rewrite"
+        }
+
+        it "converts escape sequences into styled spans for the html output" && {
+          expect_run $SUCCESSFULLY grep -q '<span class="ansi-bold ansi-red">bold red &lt;b&gt;</span> plain' "$OUTPUT_DIR/html/index.html"
+        }
+      )
+
+      (with "'expect-output' blocks and 'expect-file' tags"
+        copy-book "$fixture/books/expect-output"
+
+        it "succeeds" && {
+          expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+        }
+
+        it "shows the expected output in place of the actual one" && {
+          expect_snapshot "$snapshot/book-expect-output" "$OUTPUT_DIR/markdown-rewrite"
+        }
+      )
+
+      (with "'expect-output' blocks and 'expect-file' tags which don't match"
+        copy-book "$fixture/books/expect-output-mismatch"
+
+        it "fails and shows a diff for each mismatch" && {
+          WITH_SNAPSHOT="$snapshot/expect-output-mismatch" \
+          expect_run $WITH_FAILURE "${args[@]}" "$BOOK"
+        }
+      )
+
+      (with "'expect-output' blocks with placeholders"
+        make-book "$fixture/books/expect-placeholders.md"
+
+        it "succeeds" && {
+          expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+        }
+      )
+
+      (with "'expect-output' blocks with placeholders which don't match"
+        make-book "$fixture/books/expect-placeholders-mismatch.md"
+
+        it "fails and shows the lines which don't match" && {
+          WITH_SNAPSHOT="$snapshot/expect-placeholders-mismatch" \
+          expect_run $WITH_FAILURE "${args[@]}" "$BOOK"
+        }
+      )
+
+      (with "'expect-output' blocks which don't follow an executed block"
+        make-book "$fixture/books/expect-without-exec.md"
+
+        it "fails for each of them" && {
+          WITH_SNAPSHOT="$snapshot/expect-without-exec" \
+          expect_run $WITH_FAILURE "${args[@]}" "$BOOK"
+        }
+      )
+
+      (with "snapshot files"
+        copy-book "$fixture/books/snapshots"
+
+        it "succeeds" && {
+          expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+        }
+
+        (when "they are outdated or missing"
+          echo 'outdated' > "$BOOK/snapshots/index/greeting.out"
+          rm "$BOOK/snapshots/index/1.out"
+
+          it "fails and shows a diff for each mismatch" && {
+            WITH_SNAPSHOT="$snapshot/snapshots-mismatch" \
+            expect_run $WITH_FAILURE "${args[@]}" "$BOOK"
+          }
+
+          (with "--update-snapshots"
+            it "succeeds" && {
+              expect_run $SUCCESSFULLY "${args[@]}" --update-snapshots "$BOOK"
+            }
+
+            it "records the output of all blocks with snapshots" && {
+              expect_snapshot "$fixture/books/snapshots/snapshots" "$BOOK/snapshots"
+            }
+          )
+        )
+      )
+
+      (with "redaction rules in book.toml and 'redact' tags"
+        copy-book "$fixture/books/redact"
+
+        it "succeeds" && {
+          expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+        }
+
+        it "replaces the matching output" && {
+          expect_snapshot "$snapshot/book-redact" "$OUTPUT_DIR/markdown-rewrite"
+        }
+      )
+
+      (with "invalid 'redact' tags"
+        make-book "$fixture/books/redact-invalid.md"
+
+        it "fails" && {
+          WITH_SNAPSHOT="$snapshot/redact-invalid" \
+          expect_run $WITH_FAILURE "${args[@]}" "$BOOK"
+        }
+      )
+
+      (with "cached output"
+        copy-book "$fixture/books/cache"
+
+        it "succeeds and executes all blocks once" && {
+          WITH_SNAPSHOT="$snapshot/cache-first-build" \
+          expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+        }
+
+        it "reuses the output of unchanged blocks unless they have the 'no-cache' tag" && {
+          WITH_SNAPSHOT="$snapshot/cache-second-build" \
+          expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+        }
+
+        (with "--no-cache"
+          it "executes all blocks" && {
+            WITH_SNAPSHOT="$snapshot/cache-no-cache" \
+            expect_run $SUCCESSFULLY "${args[@]}" --no-cache "$BOOK"
+          }
+        )
+
+        (when "cleaning the book"
+          it "succeeds" && {
+            WITH_SNAPSHOT="$snapshot/cache-clean" \
+            expect_run $SUCCESSFULLY "$exe" clean "$BOOK"
+          }
+
+          it "removes the cache" && {
+            expect_run $WITH_FAILURE test -e "$BOOK/.termbook/cache"
+          }
+        )
+
+        (when "the book does not enable the cache"
+          sed -i.bak '/^cache = true/d' "$BOOK/book.toml"
+
+          it "executes all blocks on every build" && {
+            WITH_SNAPSHOT="$snapshot/cache-disabled" \
+            expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+          }
+
+          it "does not create a cache" && {
+            expect_run $WITH_FAILURE test -e "$BOOK/.termbook/cache"
+          }
+        )
+      )
+
+      (with "a hidden line prefix in book.toml"
+        copy-book "$fixture/books/hidden-lines"
+
+        it "succeeds" && {
+          WITH_SNAPSHOT="$snapshot/hidden-lines" \
+          expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+        }
+
+        it "executes hidden lines without showing them" && {
+          expect_snapshot "$snapshot/book-hidden-lines" "$OUTPUT_DIR/markdown-rewrite"
+        }
+      )
+
+      (with "strict tags and allowed tags in book.toml"
+        copy-book "$fixture/books/strict-tags"
+
+        it "fails with a suggestion for each unknown tag" && {
+          WITH_SNAPSHOT="$snapshot/strict-tags" \
+          expect_run $WITH_FAILURE "${args[@]}" "$BOOK"
+        }
+      )
+
+      (with "interpreters in book.toml and 'args' tags"
+        copy-book "$fixture/books/interpreters-and-args"
+
+        it "succeeds" && {
+          expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+        }
+
+        it "executes the configured command lines" && {
+          expect_snapshot "$snapshot/book-interpreters-and-args" "$OUTPUT_DIR/markdown-rewrite"
+        }
+      )
+
+      (with "'rust' code blocks"
+        with_program rustc
+
+        make-book "$fixture/books/rust-exec.md"
+
+        it "succeeds" && {
+          expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+        }
+
+        it "compiles and executes them" && {
+          expect_snapshot "$snapshot/book-rust-exec" "$OUTPUT_DIR/markdown-rewrite"
+        }
+      )
+
+      (with "'rust' code blocks that don't compile"
+        with_program rustc
+
+        make-book "$fixture/books/rust-exec-compile-error.md"
+
+        it "fails with the compiler errors" && {
+          WITH_SNAPSHOT="$snapshot/rust-exec-compile-error" \
+          expect_run $WITH_FAILURE "${args[@]}" "$BOOK"
+        }
+      )
+
+      (with "'rust' code blocks with a path dependency in book.toml"
+        with_program cargo
+
+        copy-book "$fixture/books/rust-exec-with-dependency"
+
+        it "succeeds" && {
+          expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+        }
+
+        it "compiles them with the dependency and executes them" && {
+          expect_snapshot "$snapshot/book-rust-exec-with-dependency" "$OUTPUT_DIR/markdown-rewrite"
+        }
+
+        (when "cargo is configured to build into another target directory"
+          export CARGO_TARGET_DIR="$BOOK/other-target"
+
+          it "still finds the compiled program" && {
+            expect_run $SUCCESSFULLY "${args[@]}" --no-cache "$BOOK"
+          }
+        )
+
+        (when "the cache is enabled and the dependency changes after a build"
+          printf '\n[preprocessor.run-code-blocks]\ncache = true\n' >> "$BOOK/book.toml"
+          expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+          sed -i.bak 's/Hello/Goodbye/' "$BOOK/greeting/src/lib.rs"
+
+          it "executes the blocks again" && {
+            expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+          }
+
+          it "shows the output of the changed dependency" && {
+            expect_snapshot "$snapshot/book-rust-exec-with-changed-dependency" "$OUTPUT_DIR/markdown-rewrite"
+          }
+        )
+      )
+
+      (with "multiple failing blocks in multiple chapters"
+        copy-book "$fixture/books/multi-chapter-with-failures"
+
+        it "fails and reports all failures in book order" && {
+          WITH_SNAPSHOT="$snapshot/exec-multi-chapter-with-failures" \
+          expect_run $WITH_FAILURE "${args[@]}" "$BOOK"
+        }
+      )
     )
 
     (with "'prepare' block"
@@ -252,6 +711,44 @@ EOF
           expect_run $WITH_FAILURE "${args[@]}" "$BOOK"
         }
       )
+      (with "multiple names of blocks defined later, which share a block they use"
+        make-book "$fixture/books/use-order-and-chains.md"
+
+        it "succeeds" && {
+          expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+        }
+
+        it "includes the blocks in order, and shared ones only once" && {
+          expect_snapshot "$snapshot/book-use-order-and-chains" "$OUTPUT_DIR/markdown-rewrite"
+        }
+      )
+      (with "blocks which use each other or a missing one"
+        make-book "$fixture/books/use-chain-failure.md"
+
+        it "fails with the chain of blocks for each" && {
+          WITH_SNAPSHOT="$snapshot/use-chain-failure" \
+          expect_run $WITH_FAILURE "${args[@]}" "$BOOK"
+        }
+      )
+      (with "a block including a file written by a block executed before"
+        make-book "$fixture/books/use-include-file.md"
+
+        it "succeeds" && {
+          expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+        }
+
+        it "executes the content of the file" && {
+          expect_snapshot "$snapshot/book-use-include-file" "$OUTPUT_DIR/markdown-rewrite"
+        }
+      )
+      (with "a block including a file which does not exist"
+        make-book "$fixture/books/use-include-file-missing.md"
+
+        it "fails for both blocks without executing anything" && {
+          WITH_SNAPSHOT="$snapshot/use-include-file-missing" \
+          expect_run $WITH_FAILURE "${args[@]}" "$BOOK"
+        }
+      )
     )
 
     (with "'hide' marker"
@@ -287,6 +784,95 @@ EOF
         }
       )
     )
+
+    (with "'hide-code', 'hide-output' and 'collapse' tags"
+      make-book "$fixture/books/hide-code-output-collapse.md"
+
+      it "succeeds" && {
+        expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+      }
+
+      it "hides either the code or the output, and shows collapsed output as usual" && {
+        expect_snapshot "$snapshot/book-hide-code-output-collapse" "$OUTPUT_DIR/markdown-rewrite"
+      }
+
+      it "puts collapsed output into details elements for the html output" && {
+        expect_run $SUCCESSFULLY grep -q '<summary>Noisy &lt;output&gt;</summary>' "$OUTPUT_DIR/html/index.html"
+        expect_run $SUCCESSFULLY grep -q '<summary>Output</summary>' "$OUTPUT_DIR/html/index.html"
+      }
+    )
+
+    (with "nested chapters"
+      copy-book "$fixture/books/nested-chapters-with-exec-and-prepare"
+
+      it "succeeds" && {
+        expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+      }
+
+      it "executes blocks in sub-chapters in book order" && {
+        expect_snapshot "$snapshot/book-nested-chapters-with-exec-and-prepare" "$OUTPUT_DIR/markdown-rewrite"
+      }
+    )
+
+    (with "temporary directories for some chapters in book.toml"
+      copy-book "$fixture/books/temp-dir-per-chapter"
+      export TMPDIR="$PWD/tmp"
+      mkdir "$TMPDIR"
+
+      it "succeeds" && {
+        expect_run $SUCCESSFULLY "${args[@]}" "$BOOK"
+      }
+
+      it "executes the programs of matching chapters in a temporary directory" && {
+        expect_snapshot "$snapshot/book-temp-dir-per-chapter" "$OUTPUT_DIR/markdown-rewrite"
+      }
+
+      it "removes the temporary directories" && {
+        expect_run $SUCCESSFULLY test -z "$(ls "$TMPDIR")"
+      }
+
+      (with "--keep-temp"
+        it "succeeds" && {
+          expect_run $SUCCESSFULLY "${args[@]}" --keep-temp "$BOOK"
+        }
+
+        it "keeps the temporary directories" && {
+          expect_run $SUCCESSFULLY test -e "$(ls -d "$TMPDIR"/termbook-* | head -n 1)"
+        }
+      )
+    )
+
+    (with "--jobs"
+      (when "chapters use 'prepare' blocks and sessions of other chapters"
+        copy-book "$fixture/books/parallel-chapters"
+
+        it "succeeds" && {
+          expect_run $SUCCESSFULLY "${args[@]}" --jobs 3 "$BOOK"
+        }
+
+        it "executes chapters sharing a session in book order" && {
+          expect_snapshot "$snapshot/book-parallel-chapters" "$OUTPUT_DIR/markdown-rewrite"
+        }
+      )
+
+      (when "multiple chapters fail"
+        copy-book "$fixture/books/parallel-chapters-failure"
+
+        it "fails and reports the errors in book order" && {
+          WITH_SNAPSHOT="$snapshot/parallel-chapters-failure" \
+          expect_run $WITH_FAILURE "${args[@]}" --jobs 2 "$BOOK"
+        }
+      )
+    )
+
+    (with "'prepare' blocks which are redefined or used in other chapters"
+      copy-book "$fixture/books/prepare-scopes-failure"
+
+      it "fails with an error for each" && {
+        WITH_SNAPSHOT="$snapshot/prepare-scopes-failure" \
+        expect_run $WITH_FAILURE "${args[@]}" "$BOOK"
+      }
+    )
   )
 )
 